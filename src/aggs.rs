@@ -0,0 +1,391 @@
+use bson::Bson;
+use chrono::prelude::*;
+use mongodb::bson::{doc, document::Document};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+
+use crate::db::DB;
+use crate::error::MyError;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+const DEFAULT_TERMS_SIZE: i64 = 10;
+
+// Longest fixed_interval, in milliseconds
+const MAX_FIXED_INTERVAL: i64 = 1000 * 365 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug)]
+pub struct Aggregation {
+    name: String,
+    kind: Kind,
+    subs: Vec<Aggregation>,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Terms { field: String, size: i64, sort: Document },
+    DateHistogram { field: String, interval: Interval },
+    Metric { operator: &'static str, field: String },
+    Cardinality { field: String },
+}
+
+#[derive(Debug)]
+enum Interval {
+    Fixed(i64),
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+// Parse the aggs section of a search request
+pub fn parse(aggs: &Value) -> BoxResult<Vec<Aggregation>> {
+    let aggs = match aggs.as_object() {
+        Some(aggs) => aggs,
+        None => return Err(MyError::bad_request("aggs expects an object of named aggregations"))
+    };
+
+    let mut result = Vec::new();
+    for (name, spec) in aggs {
+        let spec = match spec.as_object() {
+            Some(spec) => spec,
+            None => return Err(MyError::bad_request(format!("aggregation {} expects an object", name)))
+        };
+
+        let subs = match spec.get("aggs").or_else(|| spec.get("aggregations")) {
+            Some(subs) => parse(subs)?,
+            None => Vec::new()
+        };
+
+        let (kind, body) = match spec.iter().find(|(k, _)| k.as_str() != "aggs" && k.as_str() != "aggregations") {
+            Some((kind, Value::Object(body))) => (kind.as_str(), body),
+            _ => return Err(MyError::bad_request(format!("aggregation {} has no type", name)))
+        };
+
+        let kind = match kind {
+            "terms" => {
+                let order = body.get("order").and_then(Value::as_object);
+                let sort = match order.and_then(|o| o.iter().next()) {
+                    Some((key, direction)) => {
+                        let direction = if direction.as_str() == Some("asc") { 1 } else { -1 };
+                        match key.as_str() {
+                            "_key" => doc! { "_id": direction },
+                            _ => doc! { "doc_count": direction, "_id": 1 }
+                        }
+                    }
+                    None => doc! { "doc_count": -1, "_id": 1 }
+                };
+                Kind::Terms {
+                    field: field(body, name)?,
+                    size: body.get("size").and_then(Value::as_i64).unwrap_or(DEFAULT_TERMS_SIZE),
+                    sort,
+                }
+            }
+            "date_histogram" => Kind::DateHistogram {
+                field: body.get("field").and_then(Value::as_str).unwrap_or("_time").to_owned(),
+                interval: parse_interval(body)?,
+            },
+            "avg" => Kind::Metric { operator: "$avg", field: field(body, name)? },
+            "sum" => Kind::Metric { operator: "$sum", field: field(body, name)? },
+            "min" => Kind::Metric { operator: "$min", field: field(body, name)? },
+            "max" => Kind::Metric { operator: "$max", field: field(body, name)? },
+            "cardinality" => Kind::Cardinality { field: field(body, name)? },
+            other => return Err(MyError::bad_request(format!("{} aggregations are not supported", other)))
+        };
+
+        if !subs.is_empty() && !kind.is_bucket() {
+            return Err(MyError::bad_request(format!("metric aggregation {} cannot have sub-aggregations", name)))
+        };
+
+        result.push(Aggregation { name: name.clone(), kind, subs });
+    }
+    Ok(result)
+}
+
+// Run aggregations over the documents matching filter, returning Elasticsearch shaped results
+pub fn evaluate<'a>(db: &'a DB, collection: &'a str, filter: Document, aggs: &'a [Aggregation]) -> Pin<Box<dyn Future<Output = BoxResult<Document>> + Send + 'a>> {
+    Box::pin(async move {
+        if aggs.is_empty() {
+            return Ok(Document::new())
+        };
+
+        let mut facets = Document::new();
+        for agg in aggs {
+            facets.insert(agg.name.clone(), agg.pipeline());
+        }
+        let pipeline = vec![doc! { "$match": filter.clone() }, doc! { "$facet": facets }];
+        let output = db.aggregate(collection, pipeline).await?.into_iter().next().unwrap_or_default();
+
+        let mut result = Document::new();
+        for agg in aggs {
+            let rows: Vec<Document> = output
+                .get_array(&agg.name)
+                .map(|rows| rows.iter().filter_map(|r| r.as_document().cloned()).collect())
+                .unwrap_or_default();
+
+            let value = match &agg.kind {
+                Kind::Metric { .. } | Kind::Cardinality { .. } => {
+                    let value = rows.first().and_then(|r| r.get("value")).cloned().unwrap_or(Bson::Null);
+                    doc! { "value": value }
+                }
+                Kind::Terms { .. } | Kind::DateHistogram { .. } => {
+                    let mut buckets = Vec::new();
+                    for row in rows {
+                        buckets.push(agg.bucket(db, collection, &filter, row).await?);
+                    }
+                    doc! { "buckets": buckets }
+                }
+            };
+            result.insert(agg.name.clone(), value);
+        }
+        Ok(result)
+    })
+}
+
+impl Kind {
+    fn is_bucket(&self) -> bool {
+        matches!(self, Kind::Terms { .. } | Kind::DateHistogram { .. })
+    }
+}
+
+impl Aggregation {
+    // The $facet pipeline computing this aggregation
+    fn pipeline(&self) -> Vec<Document> {
+        match &self.kind {
+            Kind::Metric { operator, field } => vec![
+                doc! { "$group": { "_id": Bson::Null, "value": { *operator: format!("${}", field) } } },
+            ],
+            // Arrays are unwound so each of their values is counted, as Elasticsearch does
+            Kind::Cardinality { field } => vec![
+                doc! { "$unwind": format!("${}", field) },
+                doc! { "$group": { "_id": Bson::Null, "value": { "$addToSet": format!("${}", field) } } },
+                doc! { "$project": { "value": { "$size": "$value" } } },
+            ],
+            Kind::Terms { field, size, sort } => {
+                let mut pipeline = vec![
+                    doc! { "$match": { field: { "$exists": true } } },
+                    doc! { "$unwind": format!("${}", field) },
+                    doc! { "$group": self.group(Bson::String(format!("${}", field))) },
+                    doc! { "$sort": sort.clone() },
+                    doc! { "$limit": size },
+                ];
+                pipeline.extend(self.cardinalities());
+                pipeline
+            }
+            Kind::DateHistogram { field, interval } => {
+                let mut pipeline = vec![
+                    doc! { "$match": { field: { "$type": "date" } } },
+                    doc! { "$group": self.group(interval.key(field)) },
+                    doc! { "$sort": { "_id": 1 } },
+                ];
+                pipeline.extend(self.cardinalities());
+                pipeline
+            }
+        }
+    }
+
+    // Group into buckets, computing metric sub-aggregations as accumulators
+    fn group(&self, key: Bson) -> Document {
+        let mut group = doc! { "_id": key, "doc_count": { "$sum": 1 } };
+        for sub in self.subs.iter() {
+            match &sub.kind {
+                Kind::Metric { operator, field } => {
+                    group.insert(sub.name.clone(), doc! { *operator: format!("${}", field) });
+                }
+                Kind::Cardinality { field } => {
+                    group.insert(sub.name.clone(), doc! { "$addToSet": format!("${}", field) });
+                }
+                _ => continue
+            }
+        }
+        group
+    }
+
+    fn cardinalities(&self) -> Vec<Document> {
+        let mut sizes = Document::new();
+        for sub in self.subs.iter() {
+            if let Kind::Cardinality { .. } = sub.kind {
+                // Buckets collect whole arrays, so flatten them before counting
+                let values = doc! {
+                    "$reduce": {
+                        "input": format!("${}", sub.name),
+                        "initialValue": [],
+                        "in": { "$setUnion": ["$$value", { "$cond": [{ "$isArray": "$$this" }, "$$this", ["$$this"]] }] }
+                    }
+                };
+                sizes.insert(sub.name.clone(), doc! { "$size": values });
+            }
+        }
+        match sizes.is_empty() {
+            true => Vec::new(),
+            false => vec![doc! { "$addFields": sizes }]
+        }
+    }
+
+    async fn bucket(&self, db: &DB, collection: &str, filter: &Document, row: Document) -> BoxResult<Document> {
+        let key = row.get("_id").cloned().unwrap_or(Bson::Null);
+
+        // Narrow the filter down to this bucket, for any bucket sub-aggregations
+        let (mut bucket, condition) = match (&self.kind, &key) {
+            (Kind::DateHistogram { field, interval }, Bson::DateTime(date)) => {
+                let start = date.to_chrono();
+                let bucket = doc! {
+                    "key_as_string": start.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "key": date.timestamp_millis()
+                };
+                let condition = doc! { field: { "$gte": start, "$lt": interval.next(start) } };
+                (bucket, condition)
+            }
+            (Kind::Terms { field, .. }, key) => (doc! { "key": key.clone() }, doc! { field: key.clone() }),
+            _ => (doc! { "key": key.clone() }, doc! { "_id": Bson::Null })
+        };
+        bucket.insert("doc_count", row.get("doc_count").cloned().unwrap_or(Bson::Int32(0)));
+
+        for sub in self.subs.iter().filter(|s| !s.kind.is_bucket()) {
+            bucket.insert(sub.name.clone(), doc! { "value": row.get(&sub.name).cloned().unwrap_or(Bson::Null) });
+        }
+
+        let nested: Vec<&Aggregation> = self.subs.iter().filter(|s| s.kind.is_bucket()).collect();
+        for sub in nested {
+            let narrowed = doc! { "$and": [filter.clone(), condition.clone()] };
+            let results = evaluate(db, collection, narrowed, std::slice::from_ref(sub)).await?;
+            bucket.extend(results);
+        }
+        Ok(bucket)
+    }
+}
+
+impl Interval {
+    // Expression giving the start of the bucket a date falls in
+    fn key(&self, field: &str) -> Bson {
+        let date = format!("${}", field);
+        let expression = match self {
+            Interval::Fixed(ms) => doc! {
+                "$toDate": { "$subtract": [{ "$toLong": &date }, { "$mod": [{ "$toLong": &date }, ms] }] }
+            },
+            Interval::Week => doc! {
+                "$dateFromParts": { "isoWeekYear": { "$isoWeekYear": &date }, "isoWeek": { "$isoWeek": &date } }
+            },
+            Interval::Month => doc! {
+                "$dateFromParts": { "year": { "$year": &date }, "month": { "$month": &date } }
+            },
+            Interval::Quarter => doc! {
+                "$dateFromParts": {
+                    "year": { "$year": &date },
+                    "month": { "$toInt": { "$add": [{ "$multiply": [{ "$floor": { "$divide": [{ "$subtract": [{ "$month": &date }, 1] }, 3] } }, 3] }, 1] } }
+                }
+            },
+            Interval::Year => doc! {
+                "$dateFromParts": { "year": { "$year": &date } }
+            },
+        };
+        Bson::Document(expression)
+    }
+
+    // Start of the following bucket
+    fn next(&self, start: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Interval::Fixed(ms) => start + chrono::Duration::milliseconds(*ms),
+            Interval::Week => start + chrono::Duration::weeks(1),
+            Interval::Month => add_months(start, 1),
+            Interval::Quarter => add_months(start, 3),
+            Interval::Year => add_months(start, 12),
+        }
+    }
+}
+
+// Buckets always start on the first of the month, so the day never overflows
+fn add_months(start: DateTime<Utc>, months: u32) -> DateTime<Utc> {
+    let total = start.month0() + months;
+    let year = start.year() + (total / 12) as i32;
+    let month = total % 12 + 1;
+    match NaiveDate::from_ymd_opt(year, month, 1).and_then(|d| d.and_hms_opt(0, 0, 0)) {
+        Some(date) => Utc.from_utc_datetime(&date),
+        None => start
+    }
+}
+
+fn parse_interval(body: &Map<String, Value>) -> BoxResult<Interval> {
+    let interval = body
+        .get("calendar_interval")
+        .or_else(|| body.get("fixed_interval"))
+        .or_else(|| body.get("interval"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| MyError::bad_request("date_histogram expects a calendar_interval or fixed_interval"))?;
+
+    let interval = match interval {
+        "minute" => Interval::Fixed(60 * 1000),
+        "hour" => Interval::Fixed(60 * 60 * 1000),
+        "day" => Interval::Fixed(24 * 60 * 60 * 1000),
+        "week" | "1w" => Interval::Week,
+        "month" | "1M" => Interval::Month,
+        "quarter" | "1q" => Interval::Quarter,
+        "year" | "1y" => Interval::Year,
+        fixed => {
+            let unit_start = fixed.find(|c: char| !c.is_ascii_digit()).unwrap_or(fixed.len());
+            let amount: i64 = fixed[..unit_start].parse().map_err(|_| MyError::bad_request(format!("invalid interval {}", fixed)))?;
+            let unit = match &fixed[unit_start..] {
+                "ms" => 1,
+                "s" => 1000,
+                "m" => 60 * 1000,
+                "h" => 60 * 60 * 1000,
+                "d" => 24 * 60 * 60 * 1000,
+                _ => return Err(MyError::bad_request(format!("invalid interval {}", fixed)))
+            };
+            match amount.checked_mul(unit) {
+                Some(ms) => Interval::Fixed(ms),
+                None => return Err(MyError::bad_request(format!("interval {} is too long", fixed)))
+            }
+        }
+    };
+
+    match interval {
+        Interval::Fixed(ms) if ms <= 0 => Err(MyError::bad_request("intervals must be longer than zero")),
+        // Bucket ends are computed as dates, which only reach so far
+        Interval::Fixed(ms) if ms > MAX_FIXED_INTERVAL => Err(MyError::bad_request("fixed intervals can be at most 1000 years")),
+        interval => Ok(interval)
+    }
+}
+
+fn field(body: &Map<String, Value>, name: &str) -> BoxResult<String> {
+    match body.get("field").and_then(Value::as_str) {
+        Some(field) => Ok(field.to_owned()),
+        None => Err(MyError::bad_request(format!("aggregation {} expects a field", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn interval(value: &str) -> BoxResult<Interval> {
+        match json!({ "fixed_interval": value }) {
+            Value::Object(body) => parse_interval(&body),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn fixed_intervals_are_read_in_milliseconds() {
+        assert!(matches!(interval("90s").unwrap(), Interval::Fixed(90000)));
+        assert!(matches!(interval("2d").unwrap(), Interval::Fixed(172800000)));
+        assert!(matches!(interval("1M").unwrap(), Interval::Month));
+    }
+
+    #[test]
+    fn overflowing_intervals_are_refused() {
+        assert!(interval("9999999999999999d").is_err());
+        assert!(interval("99999999999999999999ms").is_err());
+        assert!(interval("500000d").is_err());
+    }
+
+    #[test]
+    fn empty_and_unknown_intervals_are_refused() {
+        assert!(interval("0d").is_err());
+        assert!(interval("5x").is_err());
+        assert!(interval("d").is_err());
+    }
+}
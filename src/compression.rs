@@ -0,0 +1,167 @@
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use hyper::{Body, Request, Response};
+use std::error::Error;
+use std::io::{Read, Write};
+
+use crate::error::MyError;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+// Preferred first, when the client likes several equally
+const PREFERENCE: [Encoding; 3] = [Encoding::Zstd, Encoding::Brotli, Encoding::Gzip];
+
+impl Encoding {
+    // Pick the encoding the client likes most from Accept-Encoding, like gzip, br;q=0.8
+    pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+        let accept = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+        let weights: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|coding| {
+                let mut parts = coding.split(';');
+                let name = parts.next().unwrap_or_default().trim();
+                let q = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (name, q)
+            })
+            .collect();
+
+        let weight = |encoding: Encoding| {
+            weights.iter().find(|(name, _)| encoding.matches(name))
+                .or_else(|| weights.iter().find(|(name, _)| *name == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+        PREFERENCE
+            .iter()
+            .copied()
+            .filter(|e| weight(*e) > 0.0)
+            .fold(None, |best: Option<Encoding>, e| match best {
+                Some(b) if weight(b) >= weight(e) => Some(b),
+                _ => Some(e)
+            })
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        PREFERENCE.iter().copied().find(|e| e.matches(name))
+    }
+
+    fn matches(self, name: &str) -> bool {
+        match self {
+            Encoding::Gzip => name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip"),
+            Encoding::Brotli => name.eq_ignore_ascii_case("br"),
+            Encoding::Zstd => name.eq_ignore_ascii_case("zstd"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> BoxResult<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Encoding::Brotli => {
+                let mut compressed = Vec::new();
+                {
+                    // Quality 5 compresses close to the best, at a fraction of the cost
+                    let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+                    encoder.write_all(data)?;
+                }
+                Ok(compressed)
+            }
+            Encoding::Zstd => Ok(zstd::encode_all(data, 3)?),
+        }
+    }
+
+    // Reads one byte past max_size, so a body which inflates beyond it is refused rather than read whole
+    fn decompress(self, data: &[u8], max_size: u64) -> BoxResult<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Encoding::Gzip => flate2::read::MultiGzDecoder::new(data).take(max_size + 1).read_to_end(&mut decompressed),
+            Encoding::Brotli => brotli::Decompressor::new(data, 4096).take(max_size + 1).read_to_end(&mut decompressed),
+            Encoding::Zstd => zstd::Decoder::new(data)?.take(max_size + 1).read_to_end(&mut decompressed),
+        }.map_err(|e| MyError::bad_request(format!("failed to decompress {} body: {}", self.name(), e)))?;
+        if decompressed.len() as u64 > max_size {
+            return Err(Box::new(MyError::PayloadTooLarge(max_size)))
+        };
+        Ok(decompressed)
+    }
+}
+
+// Compress a response whose whole body is known, once it is at least min_size bytes.
+// Streamed bodies, and ones which are already encoded, are sent as they are.
+pub async fn compress_response(response: Response<Body>, encoding: Option<Encoding>, min_size: u64) -> BoxResult<Response<Body>> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(response)
+    };
+    let size = match response.body().size_hint().exact() {
+        Some(size) => size,
+        None => return Ok(response)
+    };
+    if size < min_size || response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response)
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let compressed = encoding.compress(&body)?;
+    log::debug!("Compressed {} byte response to {} bytes with {}", body.len(), compressed.len(), encoding.name());
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    Ok(Response::from_parts(parts, Body::from(compressed)))
+}
+
+// Decompress a request body up front, so routes read it as it was before it was encoded
+pub async fn decompress_request(req: Request<Body>, max_size: u64) -> BoxResult<Request<Body>> {
+    if !req.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(req)
+    };
+    let (mut parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let body = decompress_body(&parts.headers, body, max_size)?;
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+// Undo the Content-Encoding of a request body, in the order the codings were applied,
+// refusing it once it grows past max_size bytes
+fn decompress_body(headers: &HeaderMap, body: Bytes, max_size: u64) -> BoxResult<Bytes> {
+    let codings = match headers.get(CONTENT_ENCODING) {
+        Some(header) => header.to_str().map_err(|_| MyError::bad_request("invalid Content-Encoding"))?,
+        None => return Ok(body)
+    };
+
+    let mut body = body;
+    for coding in codings.rsplit(',').map(str::trim).filter(|c| !c.is_empty()) {
+        if coding.eq_ignore_ascii_case("identity") {
+            continue
+        };
+        match Encoding::from_name(coding) {
+            Some(encoding) => body = Bytes::from(encoding.decompress(&body, max_size)?),
+            None => return Err(Box::new(MyError::UnsupportedEncoding(coding.to_owned())))
+        }
+    }
+    Ok(body)
+}
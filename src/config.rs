@@ -0,0 +1,167 @@
+use bson::document::Document;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub collections: HashMap<String, CollectionConfig>,
+    // Seconds between retention prunes
+    pub prune_interval: u64,
+    // Responses smaller than this many bytes are not compressed
+    pub compress_min_size: u64,
+    // Compressed request bodies which inflate past this many bytes are refused
+    pub max_body_size: u64,
+    // Indent JSON responses, unless a request passes pretty=false
+    pub pretty: bool,
+    // Wrap find and aggregate results in {took_ms, count, truncated, docs}, unless a request passes envelope=false
+    pub envelope: bool,
+    // Return _id from finds, as a hex string for ObjectIds, unless a request passes include_id=false
+    pub include_id: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            collections: HashMap::new(),
+            prune_interval: 300,
+            compress_min_size: 1024,
+            max_body_size: 16 * 1024 * 1024,
+            pretty: false,
+            envelope: false,
+            include_id: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CollectionConfig {
+    pub webhooks: Vec<WebhookConfig>,
+    // JSON Schema every inserted or updated document must match
+    pub schema: Option<Value>,
+    // Indexes to create on startup, in the same format as createIndexes
+    pub indexes: Vec<Document>,
+    // Expire documents this many seconds after their _time, through a TTL index
+    pub expire_after: Option<i64>,
+    pub capped: Option<CappedConfig>,
+    // Server side validator, ie: {"$jsonSchema": {...}}
+    pub validator: Option<Document>,
+    pub validation_level: Option<String>,
+    pub validation_action: Option<String>,
+    // Drop indexes which are not listed in the config
+    pub drop_unknown_indexes: bool,
+    pub retention: Option<RetentionConfig>,
+    // Strings to store as dates
+    pub dates: Option<DateConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DateConfig {
+    // Dotted paths like items.*.at, or JSONPath like $.items[*].at, where * matches any field
+    pub fields: Vec<String>,
+    // Convert any RFC 3339 string, wherever it is
+    pub auto: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetentionConfig {
+    // Delete documents whose _time is older than this many seconds
+    pub max_age: Option<i64>,
+    // Delete the oldest documents beyond this count
+    pub max_docs: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CappedConfig {
+    // Maximum size in bytes
+    pub size: i64,
+    // Maximum number of documents
+    pub max: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Shared secret used to sign the body with HMAC-SHA256
+    pub secret: Option<String>,
+    // Only send these top-level fields, instead of the whole document
+    pub fields: Option<Vec<String>>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> BoxResult<Self> {
+        log::debug!("Reading config file {}", path);
+        let contents = fs::read_to_string(path)?;
+        let config: Config = serde_yaml::from_str(&contents)?;
+        Ok(config)
+    }
+
+    pub fn collection(&self, name: &str) -> Option<&CollectionConfig> {
+        self.collections.get(name)
+    }
+
+    pub fn webhooks(&self, collection: &str) -> &[WebhookConfig] {
+        match self.collection(collection) {
+            Some(config) => &config.webhooks,
+            None => &[],
+        }
+    }
+
+    pub fn dates(&self, collection: &str) -> Option<&DateConfig> {
+        self.collection(collection).and_then(|c| c.dates.as_ref())
+    }
+
+    // Collections whose shape is managed by json-bucket
+    pub fn provisioned(&self) -> Vec<(&String, &CollectionConfig)> {
+        let mut provisioned: Vec<(&String, &CollectionConfig)> = self.collections
+            .iter()
+            .filter(|(_, c)| c.is_provisioned())
+            .collect();
+        provisioned.sort_by(|a, b| a.0.cmp(b.0));
+        provisioned
+    }
+
+    // Collections with a retention policy, sorted by name
+    pub fn retained(&self) -> Vec<(&String, &RetentionConfig)> {
+        let mut retained: Vec<(&String, &RetentionConfig)> = self.collections
+            .iter()
+            .filter_map(|(name, c)| c.retention.as_ref().map(|r| (name, r)))
+            .collect();
+        retained.sort_by(|a, b| a.0.cmp(b.0));
+        retained
+    }
+
+    pub fn has_webhooks(&self) -> bool {
+        self.collections.values().any(|c| !c.webhooks.is_empty())
+    }
+}
+
+impl CollectionConfig {
+    pub fn is_provisioned(&self) -> bool {
+        !self.indexes.is_empty()
+            || self.expire_after.is_some()
+            || self.capped.is_some()
+            || self.validator.is_some()
+            || self.drop_unknown_indexes
+    }
+
+    // The validator settings, named as collMod expects them
+    pub fn validator_settings(&self) -> Option<Document> {
+        let validator = self.validator.as_ref()?;
+        let mut settings = bson::doc! { "validator": validator.clone() };
+        if let Some(level) = &self.validation_level {
+            settings.insert("validationLevel", level);
+        };
+        if let Some(action) = &self.validation_action {
+            settings.insert("validationAction", action);
+        };
+        Some(settings)
+    }
+}
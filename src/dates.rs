@@ -0,0 +1,156 @@
+use bson::Bson;
+use chrono::prelude::*;
+use mongodb::bson::document::Document;
+
+use crate::config::DateConfig;
+
+// Fields managed by json-bucket and mongodb, which are never converted
+const IGNORED_FIELDS: [&str; 2] = ["_id", "_time"];
+
+// Update operators whose values are written into the document
+const WRITE_OPERATORS: [&str; 4] = ["$set", "$setOnInsert", "$push", "$addToSet"];
+
+// Convert strings matching the rules into datetimes, before a document is stored
+pub fn convert(rules: &DateConfig, mongodoc: &mut Document) {
+    let mut path = Vec::new();
+    for (key, value) in mongodoc.iter_mut() {
+        path.push(key.clone());
+        convert_value(rules, &mut path, value);
+        path.pop();
+    }
+}
+
+// Convert the values an update writes, or the whole document for replacements
+pub fn convert_update(rules: &DateConfig, update: &mut Document) {
+    if !update.keys().any(|k| k.starts_with('$')) {
+        return convert(rules, update)
+    };
+
+    for (operator, fields) in update.iter_mut() {
+        let fields = match fields {
+            Bson::Document(fields) if WRITE_OPERATORS.contains(&operator.as_str()) => fields,
+            _ => continue
+        };
+        for (key, value) in fields.iter_mut() {
+            let mut path = field_path(key);
+            convert_value(rules, &mut path, value);
+        }
+    }
+}
+
+// Turn converted datetimes back into RFC 3339 strings, for a document or a list of them
+pub fn restore(rules: &DateConfig, value: &mut Bson) {
+    match value {
+        Bson::Document(mongodoc) => restore_document(rules, mongodoc),
+        Bson::Array(values) => {
+            for value in values.iter_mut() {
+                if let Bson::Document(mongodoc) = value {
+                    restore_document(rules, mongodoc)
+                }
+            }
+        }
+        _ => ()
+    }
+}
+
+pub fn restore_document(rules: &DateConfig, mongodoc: &mut Document) {
+    let mut path = Vec::new();
+    for (key, value) in mongodoc.iter_mut() {
+        path.push(key.clone());
+        restore_value(rules, &mut path, value);
+        path.pop();
+    }
+}
+
+fn convert_value(rules: &DateConfig, path: &mut Vec<String>, value: &mut Bson) {
+    match value {
+        Bson::String(s) => {
+            if let Some(date) = parse(rules, path, s) {
+                *value = Bson::DateTime(date.into())
+            }
+        }
+        Bson::Document(mongodoc) => {
+            for (key, value) in mongodoc.iter_mut() {
+                // Operators like $each are not part of the path
+                let operator = key.starts_with('$');
+                if !operator {
+                    path.push(key.clone());
+                };
+                convert_value(rules, path, value);
+                if !operator {
+                    path.pop();
+                };
+            }
+        }
+        Bson::Array(values) => values.iter_mut().for_each(|v| convert_value(rules, path, v)),
+        _ => ()
+    }
+}
+
+fn restore_value(rules: &DateConfig, path: &mut Vec<String>, value: &mut Bson) {
+    match value {
+        Bson::DateTime(date) if applies(rules, path) => {
+            *value = Bson::String(date.to_chrono().to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        }
+        Bson::Document(mongodoc) => {
+            for (key, value) in mongodoc.iter_mut() {
+                path.push(key.clone());
+                restore_value(rules, path, value);
+                path.pop();
+            }
+        }
+        Bson::Array(values) => values.iter_mut().for_each(|v| restore_value(rules, path, v)),
+        _ => ()
+    }
+}
+
+// Listed fields accept any timestamp, while auto detection only trusts RFC 3339
+fn parse(rules: &DateConfig, path: &[String], value: &str) -> Option<DateTime<Utc>> {
+    if is_ignored(path) {
+        return None
+    };
+    if rules.fields.iter().any(|pattern| matches(pattern, path)) {
+        return parse_timestamp(value)
+    };
+    match rules.auto {
+        true => DateTime::parse_from_rfc3339(value).ok().map(|date| date.with_timezone(&Utc)),
+        false => None
+    }
+}
+
+fn applies(rules: &DateConfig, path: &[String]) -> bool {
+    !is_ignored(path) && (rules.auto || rules.fields.iter().any(|pattern| matches(pattern, path)))
+}
+
+fn is_ignored(path: &[String]) -> bool {
+    path.len() == 1 && IGNORED_FIELDS.contains(&path[0].as_str())
+}
+
+// The path an update key like items.0.at refers to, without array positions
+fn field_path(key: &str) -> Vec<String> {
+    key.split('.')
+        .filter(|segment| segment.parse::<usize>().is_err())
+        .map(str::to_owned)
+        .collect()
+}
+
+// Match a path against items.*.at or $.items[*].at, where arrays need no segment of their own
+fn matches(pattern: &str, path: &[String]) -> bool {
+    let pattern = pattern.strip_prefix("$.").unwrap_or(pattern).replace("[*]", "");
+    let segments: Vec<&str> = pattern.split('.').collect();
+    segments.len() == path.len() && segments.iter().zip(path).all(|(segment, field)| *segment == "*" || segment == field)
+}
+
+// Read an RFC 3339 timestamp, or a date and time without a timezone, taken as UTC
+pub fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Utc))
+    };
+    if let Ok(date) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Some(Utc.from_utc_datetime(&date))
+    };
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?)),
+        Err(_) => None
+    }
+}
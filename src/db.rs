@@ -1,41 +1,59 @@
 use chrono::prelude::*;
 use mongodb::bson::{doc, document::Document};
 //use mongodb::{options::ClientOptions, options::FindOptions, Client, Collection};
+use crate::config::Config;
+use crate::dates;
 use crate::error::MyError;
-use mongodb::{options::ClientOptions, options::FindOneOptions, options::FindOptions, options::UpdateOptions, Client};
+use crate::schema;
+use crate::webhooks;
+use mongodb::error::ErrorKind;
+use mongodb::{options::ClientOptions, options::CountOptions, options::FindOneAndReplaceOptions, options::FindOneAndUpdateOptions, options::FindOneOptions, options::FindOptions, options::ReplaceOptions, options::ReturnDocument, options::UpdateOptions, Client, Cursor};
 //use serde::{Deserialize, Serialize};
 use futures::StreamExt;
 use clap::ArgMatches;
+use serde_json::Value;
 use std::collections::HashMap;
 use bson::Bson;
+use bson::oid::ObjectId;
+use sha2::{Digest, Sha256};
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::Notify;
 
 #[derive(Clone, Debug)]
 pub struct DB {
     pub client: Client,
     pub db: String,
+    pub config: Arc<Config>,
+    pub webhook_notify: Arc<Notify>,
 }
 
 type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Characters escaped when an id is used in a url path
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'/').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
 //type Result<T> = std::result::Result<T, MyError>;
 
 impl DB {
-    pub async fn init(url: &str, db: &str) -> BoxResult<Self> {
+    pub async fn init(url: &str, db: &str, config: Config) -> BoxResult<Self> {
         let mut client_options = ClientOptions::parse(url).await?;
         client_options.app_name = Some("json-bucket".to_string());
         Ok(Self {
             client: Client::with_options(client_options)?,
             db: db.to_owned(),
+            config: Arc::new(config),
+            webhook_notify: Arc::new(Notify::new()),
         })
     }
 
-    pub async fn findone(&self, collection: &str, query: Document, projection: Option<Document>) -> BoxResult<Document> {
+    pub async fn findone(&self, collection: &str, query: Document, projection: Option<Document>, include_id: bool) -> BoxResult<Document> {
         // Log which collection this is going into
         log::debug!("Searching {}.{}", self.db, collection);
 
         let project = match projection {
             Some(project) => Some(project),
-            None => Some(doc! {"_id": 0})
+            None => Some(default_projection(include_id))
         };
 
         let find_one_options = FindOneOptions::builder()
@@ -63,21 +81,30 @@ impl DB {
         }
     }
 
-    pub async fn find(&self, collection: &str, query: Document, projection: Option<Document>) -> BoxResult<Vec<Document>> {
+    // Find the newest 100 matching documents, along with whether there were more
+    pub async fn find(&self, collection: &str, query: Document, projection: Option<Document>, include_id: bool) -> BoxResult<(Vec<Document>, bool)> {
         // Log which collection this is going into
         log::debug!("Searching {}.{}", self.db, collection);
 
         let project = match projection {
             Some(project) => Some(project),
-            None => Some(doc! {"_id": 0})
+            None => Some(default_projection(include_id))
         };
 
         let find_options = FindOptions::builder()
             .sort(doc! { "_id": -1 })
             .projection(project)
-            .limit(100)
             .build();
 
+        let (result, truncated) = self.find_limited(collection, query, find_options, 100).await?;
+        let result = result.into_iter().rev().collect();
+        Ok((result, truncated))
+    }
+
+    // Find documents in the order given by the options, rather than oldest first
+    pub async fn find_with_options(&self, collection: &str, query: Document, find_options: FindOptions) -> BoxResult<Vec<Document>> {
+        log::debug!("Searching {}.{}", self.db, collection);
+
         let collection = self.client.database(&self.db).collection::<Document>(collection);
         let mut cursor = collection.find(query, find_options).await?;
 
@@ -91,11 +118,88 @@ impl DB {
                 }
             }
         }
-        let result = result.into_iter().rev().collect();
         Ok(result)
     }
 
-    pub async fn insert(&self, opts: ArgMatches<'_>, collection: &str, mut mongodoc: Document) -> BoxResult<String> {
+    // Find up to limit documents in the order given by the options, along with whether there were more
+    pub async fn find_limited(&self, collection: &str, query: Document, mut find_options: FindOptions, limit: i64) -> BoxResult<(Vec<Document>, bool)> {
+        // mongodb reads everything for a limit of 0
+        if limit <= 0 {
+            return Ok((self.find_with_options(collection, query, find_options).await?, false))
+        };
+
+        // Read one more than asked for, to tell if the results were cut short
+        find_options.limit = Some(limit + 1);
+        let mut result = self.find_with_options(collection, query, find_options).await?;
+        let truncated = result.len() as i64 > limit;
+        result.truncate(limit as usize);
+        Ok((result, truncated))
+    }
+
+    // Find a page of matching documents, along with how many match in total
+    pub async fn search(&self, collection: &str, filter: Document, find_options: FindOptions) -> BoxResult<(u64, Vec<Document>)> {
+        log::debug!("Searching {}.{}", self.db, collection);
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+        let total = collection.count_documents(filter.clone(), None).await?;
+
+        // mongodb reads everything for a limit of 0, where only the count is wanted
+        if find_options.limit == Some(0) {
+            return Ok((total, Vec::new()))
+        };
+
+        let mut cursor = collection.find(filter, find_options).await?;
+
+        let mut result: Vec<Document> = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            match doc {
+                Ok(converted) => result.push(converted),
+                Err(e) => {
+                    log::error!("Caught error, skipping: {}", e);
+                    continue;
+                }
+            }
+        }
+        Ok((total, result))
+    }
+
+    // Full text search, with the most relevant documents first and their score in _score
+    pub async fn text_search(&self, collection: &str, text: Document, filter: Document, projection: Option<Document>, limit: i64, include_id: bool) -> BoxResult<(Vec<Document>, bool)> {
+        log::debug!("Text searching {}.{}", self.db, collection);
+
+        // $text fails with a vague error when there is no text index, so check first
+        let indexes = match self.get_indexes(collection).await {
+            Ok(indexes) => indexes.get_array("firstBatch")?.clone(),
+            // A collection which does not exist has no indexes at all
+            Err(e) if namespace_not_found(e.as_ref()) => Vec::new(),
+            Err(e) => return Err(e)
+        };
+        let has_text_index = indexes.iter().any(|index| {
+            index.as_document()
+                .and_then(|i| i.get_document("key").ok())
+                .map(|key| key.values().any(|v| v.as_str() == Some("text")))
+                .unwrap_or(false)
+        });
+        if !has_text_index {
+            return Err(Box::new(MyError::NoTextIndex(collection.to_owned())))
+        };
+
+        let mut project = projection.unwrap_or_else(|| default_projection(include_id));
+        project.insert("_score", doc! {"$meta": "textScore"});
+
+        let mut query = doc! {"$text": text};
+        query.extend(filter);
+
+        let find_options = FindOptions::builder()
+            .sort(doc! { "_score": { "$meta": "textScore" } })
+            .projection(project)
+            .build();
+
+        self.find_limited(collection, query, find_options, limit).await
+    }
+
+    // Insert a doc, returning it as stored, with its _id and _time
+    pub async fn insert(&self, opts: ArgMatches<'_>, collection: &str, mut mongodoc: Document) -> BoxResult<Document> {
         match opts.is_present("readonly") {
             true => {
                 log::error!("Rejecting post, as we are in readonly mode");
@@ -107,11 +211,22 @@ impl DB {
             }
         };
 
+        schema::validate(self, collection, std::slice::from_ref(&mongodoc)).await?;
+        if let Some(rules) = self.config.dates(collection) {
+            dates::convert(rules, &mut mongodoc);
+        };
+
         let now = Utc::now();
         mongodoc.insert("_time", now);
-        let collection = self.client.database(&self.db).collection::<Document>(collection);
-        match collection.insert_one(mongodoc, None).await {
-            Ok(id) => Ok(id.inserted_id.to_string()),
+        let coll = self.client.database(&self.db).collection::<Document>(collection);
+        match coll.insert_one(&mongodoc, None).await {
+            Ok(id) => {
+                if !mongodoc.contains_key("_id") {
+                    mongodoc.insert("_id", id.inserted_id.clone());
+                };
+                webhooks::enqueue(self, collection, "insert", std::slice::from_ref(&mongodoc)).await;
+                Ok(mongodoc)
+            }
             Err(e) => {
                 log::error!("Error inserting into mongodb: {}", e);
                 Err(Box::new(e))
@@ -131,16 +246,38 @@ impl DB {
             }
         };
 
+        schema::validate(self, collection, &mongodocs).await?;
+
         let now = Utc::now();
         for mongodoc in mongodocs.iter_mut() {
+            if let Some(rules) = self.config.dates(collection) {
+                dates::convert(rules, mongodoc);
+            };
             mongodoc.insert("_time", now);
+
+            // Ids are set here rather than by the driver, so webhooks know them even when a later doc fails
+            if !mongodoc.contains_key("_id") {
+                let mut with_id = doc! {"_id": ObjectId::new()};
+                with_id.extend(std::mem::take(mongodoc));
+                *mongodoc = with_id;
+            };
         };
 
-        let collection = self.client.database(&self.db).collection::<Document>(collection);
-        match collection.insert_many(mongodocs, None).await {
-            Ok(id) => Ok(id.inserted_ids),
+        let coll = self.client.database(&self.db).collection::<Document>(collection);
+        match coll.insert_many(&mongodocs, None).await {
+            Ok(id) => {
+                webhooks::enqueue(self, collection, "insert", &mongodocs).await;
+                Ok(id.inserted_ids)
+            }
             Err(e) => {
                 log::error!("Error inserting into mongodb: {}", e);
+
+                // Inserts are ordered, so docs before the first failed one were stored
+                if let ErrorKind::BulkWrite(failure) = e.kind.as_ref() {
+                    if let Some(error) = failure.write_errors.as_ref().and_then(|errors| errors.first()) {
+                        webhooks::enqueue(self, collection, "insert", &mongodocs[..error.index]).await;
+                    };
+                };
                 Err(Box::new(e))
             }
         }
@@ -160,17 +297,48 @@ impl DB {
 
         let now = Utc::now();
 
-        let filter = mongodocs[0].clone();
+        let mut filter = mongodocs[0].clone();
         let mut mongodoc = mongodocs[1].clone();
-        mongodoc.insert("_time", now);
+        // A hex string _id matches either form, so an upsert still inserts the string it was given
+        match filter.get_str("_id") {
+            Ok(id) if ObjectId::parse_str(id).is_ok() => {
+                let mut set_on_insert = mongodoc.get_document("$setOnInsert").cloned().unwrap_or_default();
+                set_on_insert.insert("_id", id);
+                mongodoc.insert("$setOnInsert", set_on_insert);
+            }
+            _ => ()
+        };
+        parse_filter_id(&mut filter);
+        schema::validate_update(self, collection, &filter, &mongodoc).await?;
+        if let Some(rules) = self.config.dates(collection) {
+            dates::convert_update(rules, &mut mongodoc);
+        };
+
+        // _time can only be set alongside other update operators through $set
+        let mut set = mongodoc.get_document("$set").cloned().unwrap_or_default();
+        set.insert("_time", now);
+        mongodoc.insert("$set", set);
 
         let update_options = UpdateOptions::builder()
             .upsert(true)
             .build();
 
-        let collection = self.client.database(&self.db).collection::<Document>(collection);
-        match collection.update_one(filter, mongodoc, update_options).await {
+        let coll = self.client.database(&self.db).collection::<Document>(collection);
+        match coll.update_one(filter.clone(), mongodoc, update_options).await {
             Ok(result) => {
+                // Send the document as it now stands to any webhooks
+                if !self.config.webhooks(collection).is_empty() {
+                    let lookup = match &result.upserted_id {
+                        Some(id) => doc! {"_id": id},
+                        None => filter
+                    };
+                    match coll.find_one(lookup, None).await {
+                        Ok(Some(updated)) => webhooks::enqueue(self, collection, "update", &[updated]).await,
+                        Ok(None) => log::warn!("Updated doc in {}.{} could not be found for webhooks", self.db, collection),
+                        Err(e) => log::error!("Error fetching updated doc for webhooks: {}", e)
+                    }
+                };
+
                 match result.upserted_id {
                     Some(_) => Ok("Created new doc".to_owned()),
                     None => Ok("Updated existing doc".to_owned())
@@ -183,6 +351,154 @@ impl DB {
         }
     }
 
+    pub async fn get_by_id(&self, collection: &str, id: &Bson) -> BoxResult<Option<Document>> {
+        log::debug!("Getting doc {} from {}.{}", id, self.db, collection);
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+        match collection.find_one(doc! {"_id": id}, None).await {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    // Replace or create a doc by id, returning whether it was created. When the current doc
+    // is passed, the replace only happens if the doc is unchanged, as the whole doc is the filter.
+    pub async fn replace_by_id(&self, opts: ArgMatches<'_>, collection: &str, id: &Bson, mut mongodoc: Document, current: Option<Document>) -> BoxResult<(bool, Document)> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting put, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Replacing doc {} in {}.{}", id, self.db, collection);
+            }
+        };
+
+        match mongodoc.get("_id") {
+            Some(body_id) if body_id != id => {
+                return Err(MyError::bad_request("_id in the body does not match the url"))
+            }
+            _ => mongodoc.insert("_id", id.clone())
+        };
+
+        schema::validate(self, collection, std::slice::from_ref(&mongodoc)).await?;
+        if let Some(rules) = self.config.dates(collection) {
+            dates::convert(rules, &mut mongodoc);
+        };
+        mongodoc.insert("_time", Utc::now());
+
+        let checked = current.is_some();
+        let filter = current.unwrap_or_else(|| doc! {"_id": id});
+        let options = FindOneAndReplaceOptions::builder()
+            .upsert(!checked)
+            .return_document(ReturnDocument::Before)
+            .build();
+
+        let coll = self.client.database(&self.db).collection::<Document>(collection);
+        let created = match coll.find_one_and_replace(filter, mongodoc.clone(), options).await {
+            Ok(Some(_)) => false,
+            Ok(None) if checked => return Err(Box::new(MyError::PreconditionFailed)),
+            Ok(None) => true,
+            Err(e) => {
+                log::error!("Error replacing doc in mongodb: {}", e);
+                return Err(Box::new(e))
+            }
+        };
+
+        let event = if created { "insert" } else { "update" };
+        webhooks::enqueue(self, collection, event, std::slice::from_ref(&mongodoc)).await;
+        Ok((created, mongodoc))
+    }
+
+    // Update a doc by id, where fields without an update operator are set
+    pub async fn patch_by_id(&self, opts: ArgMatches<'_>, collection: &str, id: &Bson, mut update: Document, current: Option<Document>) -> BoxResult<Document> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting patch, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Patching doc {} in {}.{}", id, self.db, collection);
+            }
+        };
+
+        if !update.keys().any(|k| k.starts_with('$')) {
+            update = doc! {"$set": update};
+        };
+
+        let checked = current.is_some();
+        let filter = current.unwrap_or_else(|| doc! {"_id": id});
+        schema::validate_update(self, collection, &filter, &update).await?;
+        if let Some(rules) = self.config.dates(collection) {
+            dates::convert_update(rules, &mut update);
+        };
+
+        let mut set = update.get_document("$set").cloned().unwrap_or_default();
+        set.insert("_time", Utc::now());
+        update.insert("$set", set);
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let coll = self.client.database(&self.db).collection::<Document>(collection);
+        match coll.find_one_and_update(filter, update, options).await {
+            Ok(Some(updated)) => {
+                webhooks::enqueue(self, collection, "update", std::slice::from_ref(&updated)).await;
+                Ok(updated)
+            }
+            Ok(None) if checked => Err(Box::new(MyError::PreconditionFailed)),
+            Ok(None) => Err(Box::new(MyError::NotFound(format!("no doc with _id {} in {}", id, collection)))),
+            Err(e) => {
+                log::error!("Error updating doc in mongodb: {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn delete_by_id(&self, opts: ArgMatches<'_>, collection: &str, id: &Bson, current: Option<Document>) -> BoxResult<Document> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting delete, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Deleting doc {} from {}.{}", id, self.db, collection);
+            }
+        };
+
+        let checked = current.is_some();
+        let filter = current.unwrap_or_else(|| doc! {"_id": id});
+
+        let coll = self.client.database(&self.db).collection::<Document>(collection);
+        match coll.find_one_and_delete(filter, None).await {
+            Ok(Some(deleted)) => Ok(deleted),
+            Ok(None) if checked => Err(Box::new(MyError::PreconditionFailed)),
+            Ok(None) => Err(Box::new(MyError::NotFound(format!("no doc with _id {} in {}", id, collection)))),
+            Err(e) => {
+                log::error!("Error deleting doc from mongodb: {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    // Run a pipeline, leaving the results to be read as they arrive
+    pub async fn aggregate_cursor(&self, collection: &str, pipeline: Vec<Document>) -> BoxResult<Cursor<Document>> {
+        log::debug!("Aggregating {}.{}", self.db, collection);
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+        match collection.aggregate(pipeline, None).await {
+            Ok(cursor) => Ok(cursor),
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
     pub async fn aggregate(&self, collection: &str, pipeline: Vec<Document>) -> BoxResult<Vec<Document>> {
         let collection = self.client.database(&self.db).collection::<Document>(collection);
         let mut cursor = collection.aggregate(pipeline, None).await?;
@@ -230,7 +546,7 @@ impl DB {
         match collection.estimated_document_count(None).await {
             Ok(count) => {
                 log::debug!("Successfully counted docs in {}", self.db);
-                let result = doc! {"docs" : count.to_string()};
+                let result = doc! {"docs" : count as i64};
                 Ok(result)
             }
             Err(e) => {
@@ -240,6 +556,70 @@ impl DB {
         }
     }
 
+    pub async fn count_documents(&self, collection: &str, filter: Document, options: CountOptions) -> BoxResult<Document> {
+        log::debug!("Counting matching documents in {}.{}", self.db, collection);
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+
+        match collection.count_documents(filter, options).await {
+            Ok(count) => {
+                log::debug!("Successfully counted docs in {}", self.db);
+                let result = doc! {"docs" : count as i64};
+                Ok(result)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn distinct(&self, collection: &str, field: &str, filter: Document) -> BoxResult<Vec<Bson>> {
+        log::debug!("Getting distinct values of {} in {}.{}", field, self.db, collection);
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+
+        match collection.distinct(field, filter, None).await {
+            Ok(values) => {
+                log::debug!("Found {} distinct values", values.len());
+                Ok(values)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    // Distinct values along with how often they occur, most frequent first
+    pub async fn distinct_counts(&self, collection: &str, field: &str, filter: Document) -> BoxResult<Vec<Document>> {
+        log::debug!("Counting distinct values of {} in {}.{}", field, self.db, collection);
+
+        let pipeline = vec![
+            doc! { "$match": filter },
+            // Count each value of an array on its own, as distinct does
+            doc! { "$unwind": format!("${}", field) },
+            doc! { "$group": { "_id": format!("${}", field), "count": { "$sum": 1 } } },
+            doc! { "$sort": { "count": -1, "_id": 1 } },
+            doc! { "$project": { "_id": 0, "value": "$_id", "count": 1 } },
+        ];
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+        let mut cursor = collection.aggregate(pipeline, None).await?;
+
+        let mut result: Vec<Document> = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            match doc {
+                Ok(converted) => result.push(converted),
+                Err(e) => {
+                    log::error!("Caught error, skipping: {}", e);
+                    continue;
+                }
+            }
+        }
+        Ok(result)
+    }
+
     pub async fn get_indexes(&self, collection: &str) -> BoxResult<Document> {
         // Log that we are trying to list collections
         log::debug!("Getting indexes in {}", self.db);
@@ -260,6 +640,254 @@ impl DB {
         }
     }
 
+    pub async fn get_validator(&self, collection: &str) -> BoxResult<Document> {
+        log::debug!("Getting validator for {}.{}", self.db, collection);
+
+        let database = self.client.database(&self.db);
+        let command = doc! { "listCollections": 1, "filter": { "name": collection } };
+
+        match database.run_command(command, None).await {
+            Ok(output) => {
+                log::debug!("Successfully listed {}.{}", self.db, collection);
+                let batch = output.get_document("cursor").and_then(|c| c.get_array("firstBatch")).expect("Successfully listed collections, but failed to extract cursor");
+                let options = match batch.first().and_then(|c| c.as_document()) {
+                    Some(info) => info.get_document("options").cloned().unwrap_or_default(),
+                    None => return Ok(doc! { "msg": "collection not found" })
+                };
+
+                let mut result = Document::new();
+                for field in ["validator", "validationLevel", "validationAction"].iter() {
+                    if let Some(value) = options.get(field) {
+                        result.insert(*field, value.clone());
+                    }
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn set_validator(&self, opts: ArgMatches<'_>, collection: &str, settings: Document) -> BoxResult<String> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting validator update, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Setting validator for {}.{}", self.db, collection);
+            }
+        };
+
+        const SETTINGS: [&str; 3] = ["validator", "validationLevel", "validationAction"];
+        if !SETTINGS.iter().any(|field| settings.contains_key(field)) {
+            return Err(MyError::bad_request("expected at least one of validator, validationLevel or validationAction"))
+        };
+
+        // collMod only works on existing collections, so create the collection if needed
+        let exists = self.collections().await?.iter().any(|c| c == collection);
+        let mut command = match exists {
+            true => doc! { "collMod": collection },
+            false => doc! { "create": collection }
+        };
+        for field in SETTINGS.iter() {
+            if let Some(value) = settings.get(field) {
+                command.insert(*field, value.clone());
+            }
+        }
+
+        let database = self.client.database(&self.db);
+        match database.run_command(command, None).await {
+            Ok(_) => {
+                log::debug!("Successfully set validator for {}.{}", self.db, collection);
+                match exists {
+                    true => Ok("Updated validator".to_owned()),
+                    false => Ok("Created collection with validator".to_owned())
+                }
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn create_collection(&self, opts: ArgMatches<'_>, collection: &str, options: Document) -> BoxResult<Document> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting collection creation, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Creating collection {}.{}", self.db, collection);
+            }
+        };
+
+        // Only options of the collection itself pass through, so the body cannot create another collection or a view
+        const OPTIONS: [&str; 8] = ["capped", "size", "max", "validator", "validationLevel", "validationAction", "expireAfterSeconds", "timeseries"];
+        if let Some(key) = options.keys().find(|key| !OPTIONS.contains(&key.as_str())) {
+            return Err(MyError::bad_request(format!("unsupported collection option {}, use {}", key, OPTIONS.join(", "))))
+        };
+
+        let database = self.client.database(&self.db);
+        let mut command = doc! { "create": collection };
+        command.extend(options);
+
+        match database.run_command(command, None).await {
+            Ok(output) => {
+                log::debug!("Successfully created {}.{}", self.db, collection);
+                Ok(output)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn drop_collection(&self, opts: ArgMatches<'_>, collection: &str) -> BoxResult<()> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting collection drop, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Dropping collection {}.{}", self.db, collection);
+            }
+        };
+
+        let collection = self.client.database(&self.db).collection::<Document>(collection);
+
+        match collection.drop(None).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn rename_collection(&self, opts: ArgMatches<'_>, collection: &str, to: &str, drop_target: bool) -> BoxResult<()> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting collection rename, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Renaming collection {}.{} to {}", self.db, collection, to);
+            }
+        };
+
+        // renameCollection must be run against the admin database
+        let database = self.client.database("admin");
+        let command = doc! {
+            "renameCollection": format!("{}.{}", self.db, collection),
+            "to": format!("{}.{}", self.db, to),
+            "dropTarget": drop_target
+        };
+
+        match database.run_command(command, None).await {
+            Ok(_) => {
+                log::debug!("Successfully renamed {}.{} to {}", self.db, collection, to);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn stats(&self, collection: &str) -> BoxResult<Document> {
+        log::debug!("Getting collStats for {}.{}", self.db, collection);
+
+        let database = self.client.database(&self.db);
+        let command = doc! { "collStats": collection };
+
+        match database.run_command(command, None).await {
+            Ok(output) => {
+                log::debug!("Successfully got collStats for {}.{}", self.db, collection);
+                // Leave out the storage engine internals
+                let mut result = Document::new();
+                for field in ["ns", "count", "size", "avgObjSize", "storageSize", "freeStorageSize", "nindexes", "totalIndexSize", "totalSize", "indexSizes", "capped", "max", "maxSize"].iter() {
+                    if let Some(value) = output.get(field) {
+                        result.insert(*field, value.clone());
+                    }
+                }
+                Ok(result)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn create_indexes(&self, opts: ArgMatches<'_>, collection: &str, mut indexes: Vec<Document>) -> BoxResult<Document> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting index creation, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Creating indexes in {}.{}", self.db, collection);
+            }
+        };
+
+        for index in indexes.iter_mut() {
+            let keys = match index.get_document("key") {
+                Ok(keys) => keys.clone(),
+                Err(_) => return Err(MyError::bad_request("index is missing a key document"))
+            };
+            // The createIndexes command requires a name, so default to the one mongodb would generate
+            if !index.contains_key("name") {
+                index.insert("name", index_name(&keys));
+            };
+        }
+
+        let database = self.client.database(&self.db);
+        let command = doc! { "createIndexes": collection, "indexes": indexes };
+
+        match database.run_command(command, None).await {
+            Ok(output) => {
+                log::debug!("Successfully created indexes in {}.{}", self.db, collection);
+                Ok(output)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    pub async fn drop_index(&self, opts: ArgMatches<'_>, collection: &str, name: &str) -> BoxResult<Document> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting index drop, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Dropping index {} in {}.{}", name, self.db, collection);
+            }
+        };
+
+        let database = self.client.database(&self.db);
+        let command = doc! { "dropIndexes": collection, "index": name };
+
+        match database.run_command(command, None).await {
+            Ok(output) => {
+                log::debug!("Successfully dropped index {} in {}.{}", name, self.db, collection);
+                Ok(output)
+            }
+            Err(e) => {
+                log::error!("Got error {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+
     pub async fn rs_status(&self) -> BoxResult<Document> {
         // Log that we are trying to list collections
         log::debug!("Getting replSetGetStatus");
@@ -375,4 +1003,206 @@ impl DB {
             }
         }
     }
+
+    pub async fn get_schema(&self, collection: &str) -> BoxResult<Option<Value>> {
+        log::debug!("Getting schema for {}.{}", self.db, collection);
+
+        let schemas = self.client.database(&self.db).collection::<Document>(schema::SCHEMA_COLLECTION);
+
+        match schemas.find_one(doc! {"_id": collection}, None).await? {
+            Some(stored) => {
+                let schema: Value = serde_json::from_str(stored.get_str("schema")?)?;
+                Ok(Some(schema))
+            }
+            None => Ok(self.config.collection(collection).and_then(|c| c.schema.clone()))
+        }
+    }
+
+    pub async fn set_schema(&self, opts: ArgMatches<'_>, collection: &str, new_schema: Value) -> BoxResult<()> {
+        match opts.is_present("readonly") {
+            true => {
+                log::error!("Rejecting schema update, as we are in readonly mode");
+                return Err(Box::new(MyError::ReadOnly))
+            }
+            _ => {
+                log::debug!("Setting schema for {}.{}", self.db, collection);
+            }
+        };
+
+        // Refuse to store schemas that could never be used
+        schema::compile(&new_schema)?;
+
+        // Schemas are stored as strings, as their $ prefixed keywords are not valid field names
+        let stored = doc! {
+            "_id": collection,
+            "schema": new_schema.to_string(),
+            "updated": Utc::now()
+        };
+
+        let replace_options = ReplaceOptions::builder()
+            .upsert(true)
+            .build();
+
+        let schemas = self.client.database(&self.db).collection::<Document>(schema::SCHEMA_COLLECTION);
+        match schemas.replace_one(doc! {"_id": collection}, stored, replace_options).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("Error saving schema: {}", e);
+                Err(Box::new(e))
+            }
+        }
+    }
+}
+
+// Generate an index name the same way mongodb does, ie: title_1_time_-1
+pub fn index_name(keys: &Document) -> String {
+    keys.iter()
+        .map(|(field, direction)| match direction {
+            Bson::String(kind) => format!("{}_{}", field, kind),
+            Bson::Int32(i) => format!("{}_{}", field, i),
+            Bson::Int64(i) => format!("{}_{}", field, i),
+            Bson::Double(d) => format!("{}_{}", field, d),
+            other => format!("{}_{}", field, other)
+        })
+        .collect::<Vec<String>>()
+        .join("_")
+}
+
+// Read an _id from a url segment. 24 hex characters are an ObjectId, and other
+// types are given with a prefix: oid:, s: for strings, i: for integers and f: for floats.
+pub fn parse_id(segment: &str) -> BoxResult<Bson> {
+    let segment = percent_encoding::percent_decode_str(segment).decode_utf8()?;
+    let invalid = |kind: &str| -> Box<dyn Error + Send + Sync> {
+        MyError::bad_request(format!("{} is not a valid {} id", segment, kind))
+    };
+
+    match segment.split_once(':') {
+        Some(("oid", hex)) => ObjectId::parse_str(hex).map(Bson::ObjectId).map_err(|_| invalid("ObjectId")),
+        Some(("s", s)) => Ok(Bson::String(s.to_owned())),
+        Some(("i", i)) => i.parse::<i64>().map(Bson::Int64).map_err(|_| invalid("integer")),
+        Some(("f", f)) => f.parse::<f64>().map(Bson::Double).map_err(|_| invalid("float")),
+        _ => match ObjectId::parse_str(&segment) {
+            Ok(oid) => Ok(Bson::ObjectId(oid)),
+            Err(_) => Ok(Bson::String(segment.to_string()))
+        }
+    }
+}
+
+// The url segment for an _id, which parse_id reads back
+pub fn id_to_path(id: &Bson) -> String {
+    let segment = match id {
+        Bson::ObjectId(oid) => oid.to_hex(),
+        Bson::Int32(i) => format!("i:{}", i),
+        Bson::Int64(i) => format!("i:{}", i),
+        Bson::Double(f) => format!("f:{}", f),
+        Bson::String(s) if s.starts_with('_') || s.contains(':') || ObjectId::parse_str(s).is_ok() => format!("s:{}", s),
+        other => id_to_string(other)
+    };
+    percent_encoding::utf8_percent_encode(&segment, PATH_SEGMENT).to_string()
+}
+
+// A strong ETag for a doc, which changes whenever any of its fields do
+pub fn etag(mongodoc: &Document) -> BoxResult<String> {
+    let bytes = bson::to_vec(mongodoc)?;
+    Ok(format!("\"{}\"", hex::encode(Sha256::digest(&bytes))))
+}
+
+// Finds leave out _id, unless a projection or include_id asks for it
+pub fn default_projection(include_id: bool) -> Document {
+    match include_id {
+        true => doc! {},
+        false => doc! {"_id": 0}
+    }
+}
+
+// Match a hex string _id in a filter as either that string or the ObjectId it may have been written out from,
+// like {"_id": "61a4..."} or {"_id": {"$in": [...]}}, so collections keyed by hex strings still match
+pub fn parse_filter_id(filter: &mut Document) {
+    fn both(values: &[Bson]) -> Vec<Bson> {
+        let mut result = Vec::new();
+        for value in values {
+            result.push(value.clone());
+            if let Bson::String(s) = value {
+                if let Ok(oid) = ObjectId::parse_str(s.as_str()) {
+                    result.push(Bson::ObjectId(oid))
+                }
+            }
+        }
+        result
+    }
+
+    let id = match filter.get_mut("_id") {
+        Some(id) => id,
+        None => return
+    };
+    match id {
+        Bson::String(_) => {
+            let values = both(std::slice::from_ref(id));
+            if values.len() > 1 {
+                *id = Bson::Document(doc! { "$in": values })
+            }
+        }
+        Bson::Document(operators) => {
+            let mut parsed = Document::new();
+            for (operator, value) in operators.iter() {
+                // $eq and $ne only take one value, so become $in and $nin unless those are already there
+                let (operator, value) = match (operator.as_str(), value) {
+                    ("$eq", Bson::String(_)) if !operators.contains_key("$in") => ("$in", Bson::Array(both(std::slice::from_ref(value)))),
+                    ("$ne", Bson::String(_)) if !operators.contains_key("$nin") => ("$nin", Bson::Array(both(std::slice::from_ref(value)))),
+                    ("$in", Bson::Array(values)) | ("$nin", Bson::Array(values)) => (operator.as_str(), Bson::Array(both(values))),
+                    (operator, value) => (operator, value.clone())
+                };
+                parsed.insert(operator, value);
+            }
+            *operators = parsed;
+        }
+        _ => ()
+    }
+}
+
+// Whether mongodb failed because the collection does not exist
+fn namespace_not_found(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    match e.downcast_ref::<mongodb::error::Error>().map(|e| e.kind.as_ref()) {
+        Some(ErrorKind::Command(command)) => command.code == 26,
+        _ => false
+    }
+}
+
+// Render an _id as a plain string, using the hex form for ObjectIds
+pub fn id_to_string(id: &Bson) -> String {
+    match id {
+        Bson::ObjectId(oid) => oid.to_hex(),
+        Bson::String(s) => s.clone(),
+        other => other.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "61a4f2b9c1d2e3f4a5b6c7d8";
+
+    fn parsed(filter: Document) -> Document {
+        let mut filter = filter;
+        parse_filter_id(&mut filter);
+        filter
+    }
+
+    #[test]
+    fn hex_string_ids_match_the_string_or_the_object_id() {
+        let oid = ObjectId::parse_str(HEX).unwrap();
+        assert_eq!(parsed(doc! { "_id": HEX, "n": 1 }), doc! { "_id": { "$in": [HEX, oid] }, "n": 1 });
+        assert_eq!(parsed(doc! { "_id": { "$eq": HEX } }), doc! { "_id": { "$in": [HEX, oid] } });
+        assert_eq!(parsed(doc! { "_id": { "$ne": HEX } }), doc! { "_id": { "$nin": [HEX, oid] } });
+        assert_eq!(parsed(doc! { "_id": { "$in": [HEX, "a"] } }), doc! { "_id": { "$in": [HEX, oid, "a"] } });
+    }
+
+    #[test]
+    fn other_ids_are_left_alone() {
+        assert_eq!(parsed(doc! { "_id": "order-17" }), doc! { "_id": "order-17" });
+        assert_eq!(parsed(doc! { "_id": 17 }), doc! { "_id": 17 });
+        assert_eq!(parsed(doc! { "_id": { "$gt": HEX } }), doc! { "_id": { "$gt": HEX } });
+        assert_eq!(parsed(doc! { "name": HEX }), doc! { "name": HEX });
+    }
 }
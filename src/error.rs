@@ -1,3 +1,5 @@
+use bson::document::Document;
+use hyper::StatusCode;
 use serde_json::error::Error as SerdeError;
 use std::fmt;
 use std::str::Utf8Error;
@@ -11,13 +13,22 @@ pub enum MyError {
     UrlParseError,
     BsonError,
     ReadOnly,
+    ValidationError(Vec<Document>),
+    InvalidSchema(String),
+    Forbidden,
+    BadRequest(String),
+    NoTextIndex(String),
+    NotFound(String),
+    PreconditionFailed,
+    UnsupportedEncoding(String),
+    PayloadTooLarge(u64),
 }
 
 impl std::error::Error for MyError {}
 
 impl fmt::Display for MyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        match self {
             MyError::JsonError => f.write_str("Error converting data to json"),
             MyError::HyperError => f.write_str("Hyper Error"),
             MyError::UtfError => f.write_str("Utf conversion Error"),
@@ -25,10 +36,50 @@ impl fmt::Display for MyError {
             MyError::UrlParseError => f.write_str("Failed to parse url Error"),
             MyError::BsonError => f.write_str("Could not parse as bson doc"),
             MyError::ReadOnly=> f.write_str("Running in read only mode"),
+            MyError::ValidationError(_) => f.write_str("Document failed schema validation"),
+            MyError::InvalidSchema(e) => write!(f, "Invalid JSON schema: {}", e),
+            MyError::Forbidden => f.write_str("Admin token required"),
+            MyError::BadRequest(e) => write!(f, "Bad request: {}", e),
+            MyError::NoTextIndex(c) => write!(f, "Collection {} has no text index, create one to use _text_search", c),
+            MyError::NotFound(e) => write!(f, "Not found: {}", e),
+            MyError::PreconditionFailed => f.write_str("Document has changed since it was read, as If-Match did not match"),
+            MyError::UnsupportedEncoding(e) => write!(f, "Unsupported Content-Encoding {}, use gzip, br or zstd", e),
+            MyError::PayloadTooLarge(max) => write!(f, "Request body is larger than {} bytes once decompressed", max),
         }
     }
 }
 
+impl MyError {
+    // A 400 with a message saying what was wrong with the request
+    pub fn bad_request(message: impl Into<String>) -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(MyError::BadRequest(message.into()))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MyError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            MyError::InvalidSchema(_) => StatusCode::BAD_REQUEST,
+            MyError::Forbidden => StatusCode::FORBIDDEN,
+            MyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            MyError::NoTextIndex(_) => StatusCode::BAD_REQUEST,
+            MyError::NotFound(_) => StatusCode::NOT_FOUND,
+            MyError::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+            MyError::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            MyError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Body returned to the client, with any details the error carries
+    pub fn to_json(&self) -> String {
+        let mut body = bson::doc! { "error": self.to_string() };
+        if let MyError::ValidationError(documents) = self {
+            body.insert("documents", documents.clone());
+        };
+        serde_json::to_string(&body).expect("failed converting error to json")
+    }
+}
+
 impl From<SerdeError> for MyError {
     fn from(e: SerdeError) -> Self {
         match e {
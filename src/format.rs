@@ -0,0 +1,337 @@
+use bson::Bson;
+use chrono::SecondsFormat;
+use hyper::header::{HeaderMap, ACCEPT, CONTENT_TYPE};
+use hyper::Uri;
+use mongodb::bson::{doc, document::Document};
+use serde::Serialize;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use crate::config::DateConfig;
+use crate::dates;
+use crate::error::MyError;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// How documents are written out
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+    // Plain JSON, with ObjectIds as hex strings and dates as RFC 3339 strings
+    Plain,
+    // Relaxed Extended JSON, which keeps types that JSON has no way to express
+    #[default]
+    Relaxed,
+    // Canonical Extended JSON, which also keeps the exact numeric type of every number
+    Canonical,
+    // Tables with a column per field, for find and aggregate results. Other results fall back to relaxed.
+    Csv,
+    Tsv,
+    // YAML, written from relaxed Extended JSON
+    Yaml,
+    // Raw BSON documents, one after another, with no conversion at all
+    Bson,
+    // MessagePack, with BSON types kept as their Extended JSON objects
+    Msgpack,
+}
+
+impl Format {
+    // Pick the format from ?format=, or a format parameter on the Accept header,
+    // like Accept: application/json; format=canonical
+    pub fn negotiate(uri: &Uri, headers: &HeaderMap) -> BoxResult<Format> {
+        let param = uri.query().and_then(|q| {
+            url::form_urlencoded::parse(q.as_bytes()).find(|(k, _)| k == "format").map(|(_, v)| v.into_owned())
+        });
+        if let Some(name) = param {
+            return Format::from_name(&name)
+        };
+
+        let accept = match headers.get(ACCEPT).and_then(|h| h.to_str().ok()) {
+            Some(accept) => accept,
+            None => return Ok(Format::default())
+        };
+        let name = accept
+            .split(',')
+            .flat_map(|range| range.split(';').skip(1))
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(k, _)| k.trim() == "format")
+            .map(|(_, v)| v.trim().trim_matches('"').to_owned());
+        if let Some(name) = name {
+            return Format::from_name(&name)
+        };
+
+        // Otherwise use the first media type we know
+        let format = accept
+            .split(',')
+            .filter_map(|range| range.split(';').next())
+            .find_map(|media_type| match media_type.trim() {
+                "text/csv" => Some(Format::Csv),
+                "text/tab-separated-values" => Some(Format::Tsv),
+                "application/yaml" | "application/x-yaml" | "text/yaml" => Some(Format::Yaml),
+                "application/bson" => Some(Format::Bson),
+                "application/msgpack" | "application/x-msgpack" => Some(Format::Msgpack),
+                "application/json" => Some(Format::Relaxed),
+                _ => None
+            });
+        Ok(format.unwrap_or_default())
+    }
+
+    fn from_name(name: &str) -> BoxResult<Format> {
+        match name {
+            "plain" => Ok(Format::Plain),
+            "relaxed" => Ok(Format::Relaxed),
+            "canonical" => Ok(Format::Canonical),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "yaml" => Ok(Format::Yaml),
+            "bson" => Ok(Format::Bson),
+            "msgpack" => Ok(Format::Msgpack),
+            _ => Err(MyError::bad_request(format!("unknown format {}, use plain, relaxed, canonical, csv, tsv, yaml, bson or msgpack", name)))
+        }
+    }
+
+    pub fn to_value(self, value: Bson) -> Value {
+        match self {
+            Format::Plain => plain(value),
+            Format::Canonical => value.into_canonical_extjson(),
+            _ => value.into_relaxed_extjson(),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Tsv => "text/tab-separated-values; charset=utf-8",
+            Format::Yaml => "application/yaml",
+            Format::Bson => "application/bson",
+            Format::Msgpack => "application/msgpack",
+            _ => "application/json"
+        }
+    }
+
+    // The cell delimiter, for formats which are tables
+    pub fn delimiter(self) -> Option<u8> {
+        match self {
+            Format::Csv => Some(b','),
+            Format::Tsv => Some(b'\t'),
+            _ => None
+        }
+    }
+
+    // Formats which carry BSON types as they are, so dates are never turned back into strings
+    fn keeps_types(self) -> bool {
+        matches!(self, Format::Canonical | Format::Bson | Format::Msgpack)
+    }
+}
+
+// How a response is written out, for the collection it came from
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub format: Format,
+    // Dates stored through these rules are written back out as strings, unless canonical
+    pub dates: Option<DateConfig>,
+    // Indent JSON
+    pub pretty: bool,
+    // Wrap lists of results in {took_ms, count, truncated, docs}
+    pub envelope: bool,
+    // Write ObjectId _ids as hex strings, which update filters read back
+    pub string_ids: bool,
+}
+
+impl Output {
+    pub fn render<T: Serialize>(&self, value: &T) -> BoxResult<Vec<u8>> {
+        let mut value = bson::to_bson(value)?;
+        self.prepare(&mut value);
+        self.write(value)
+    }
+
+    // Render find or aggregate results, in an envelope when asked for
+    pub fn render_list(&self, docs: Vec<Document>, truncated: bool, took_ms: i64) -> BoxResult<Vec<u8>> {
+        if !self.envelope {
+            return self.render(&docs)
+        };
+        let count = docs.len() as i64;
+        let mut docs = Bson::from(docs);
+        self.prepare(&mut docs);
+        self.write(Bson::Document(doc! {
+            "took_ms": took_ms,
+            "count": count,
+            "truncated": truncated,
+            "docs": docs,
+        }))
+    }
+
+    // Restore dates in documents before they are nested in a response, like search hits,
+    // where the configured paths no longer line up
+    pub fn restore_dates(&self, docs: &mut [Document]) {
+        if let (Some(rules), false) = (&self.dates, self.format.keeps_types()) {
+            docs.iter_mut().for_each(|mongodoc| dates::restore_document(rules, mongodoc));
+        };
+    }
+
+    // Restore dates and stringify ids, for a document or a list of them
+    fn prepare(&self, value: &mut Bson) {
+        if let (Some(rules), false) = (&self.dates, self.format.keeps_types()) {
+            dates::restore(rules, value);
+        };
+        if self.string_ids {
+            match value {
+                Bson::Document(doc) => stringify_id(doc),
+                Bson::Array(values) => values.iter_mut().filter_map(Bson::as_document_mut).for_each(stringify_id),
+                _ => ()
+            }
+        };
+    }
+
+    fn write(&self, value: Bson) -> BoxResult<Vec<u8>> {
+        match self.format {
+            Format::Bson => to_bson_bytes(value),
+            Format::Msgpack => Ok(rmp_serde::to_vec_named(&value)?),
+            Format::Yaml => Ok(serde_yaml::to_string(&self.format.to_value(value))?.into_bytes()),
+            _ if self.pretty => Ok(serde_json::to_vec_pretty(&self.format.to_value(value))?),
+            _ => Ok(serde_json::to_vec(&self.format.to_value(value))?)
+        }
+    }
+}
+
+fn stringify_id(mongodoc: &mut Document) {
+    if let Some(Bson::ObjectId(oid)) = mongodoc.get("_id") {
+        let hex = oid.to_hex();
+        mongodoc.insert("_id", hex);
+    };
+}
+
+// Read an on or off query parameter like ?pretty=true, where ?pretty alone means on
+pub fn flag(uri: &Uri, name: &str) -> BoxResult<Option<bool>> {
+    let value = uri.query().and_then(|q| {
+        url::form_urlencoded::parse(q.as_bytes()).find(|(k, _)| k == name).map(|(_, v)| v.into_owned())
+    });
+    match value.as_deref() {
+        None => Ok(None),
+        Some("") | Some("true") | Some("1") => Ok(Some(true)),
+        Some("false") | Some("0") => Ok(Some(false)),
+        Some(other) => Err(MyError::bad_request(format!("{} should be true or false, not {}", name, other)))
+    }
+}
+
+// A document as is, a list of documents one after another, and anything else wrapped as {"value": ...}
+fn to_bson_bytes(value: Bson) -> BoxResult<Vec<u8>> {
+    let docs = match value {
+        Bson::Document(doc) => vec![doc],
+        Bson::Array(values) if values.iter().all(|v| matches!(v, Bson::Document(_))) => {
+            values.into_iter().filter_map(|v| match v {
+                Bson::Document(doc) => Some(doc),
+                _ => None
+            }).collect()
+        }
+        value => vec![doc! { "value": value }]
+    };
+    let mut bytes = Vec::new();
+    for doc in docs.iter() {
+        doc.to_writer(&mut bytes)?;
+    }
+    Ok(bytes)
+}
+
+fn plain(value: Bson) -> Value {
+    match value {
+        Bson::Document(doc) => Value::Object(doc.into_iter().map(|(k, v)| (k, plain(v))).collect()),
+        Bson::Array(values) => Value::Array(values.into_iter().map(plain).collect()),
+        Bson::ObjectId(oid) => Value::String(oid.to_hex()),
+        Bson::DateTime(date) => Value::String(date.to_chrono().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        Bson::Int32(i) => Value::from(i),
+        Bson::Int64(i) => Value::from(i),
+        Bson::Double(f) if f.is_finite() => Value::from(f),
+        other => other.into_relaxed_extjson()
+    }
+}
+
+// Formats a request body can be sent in, picked by its Content-Type
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Input {
+    // JSON, where Extended JSON like {"$date": ...} or {"$oid": ...} becomes the real type
+    #[default]
+    Json,
+    // YAML, read the same way as JSON
+    Yaml,
+    // Raw BSON documents, one after another
+    Bson,
+    // MessagePack, where Extended JSON objects become the real type
+    Msgpack,
+}
+
+impl Input {
+    pub fn from_headers(headers: &HeaderMap) -> Input {
+        let media_type = headers.get(CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split(';').next())
+            .map(str::trim);
+        match media_type {
+            Some("application/yaml") | Some("application/x-yaml") | Some("text/yaml") => Input::Yaml,
+            Some("application/bson") => Input::Bson,
+            Some("application/msgpack") | Some("application/x-msgpack") => Input::Msgpack,
+            _ => Input::Json
+        }
+    }
+
+    pub fn parse_value(self, body: &[u8]) -> BoxResult<Value> {
+        match self {
+            Input::Json => serde_json::from_slice(body).map_err(|e| MyError::bad_request(format!("invalid JSON: {}", e))),
+            Input::Yaml => serde_yaml::from_slice(body).map_err(|e| MyError::bad_request(format!("invalid YAML: {}", e))),
+            Input::Bson | Input::Msgpack => Ok(self.parse_bson(body)?.into_relaxed_extjson()),
+        }
+    }
+
+    pub fn parse_bson(self, body: &[u8]) -> BoxResult<Bson> {
+        match self {
+            Input::Bson => read_bson_docs(body),
+            Input::Msgpack => rmp_serde::from_slice(body).map_err(|e| MyError::bad_request(format!("invalid MessagePack: {}", e))),
+            _ => match Bson::try_from(self.parse_value(body)?) {
+                Ok(bson) => Ok(bson),
+                Err(e) => Err(MyError::bad_request(format!("invalid Extended JSON: {}", e)))
+            }
+        }
+    }
+
+    pub fn parse_doc(self, body: &[u8]) -> BoxResult<Document> {
+        match self.parse_bson(body)? {
+            Bson::Document(doc) => Ok(doc),
+            _ => Err(MyError::bad_request("expected an object"))
+        }
+    }
+
+    pub fn parse_doc_vec(self, body: &[u8]) -> BoxResult<Vec<Document>> {
+        match self.parse_bson(body)? {
+            Bson::Document(doc) if self == Input::Bson => Ok(vec![doc]),
+            Bson::Array(values) => values
+                .into_iter()
+                .map(|v| match v {
+                    Bson::Document(doc) => Ok(doc),
+                    _ => Err(MyError::bad_request("expected a list of objects"))
+                })
+                .collect(),
+            _ => Err(MyError::bad_request("expected a list of objects"))
+        }
+    }
+}
+
+// A single document, or a list when several are sent one after another
+fn read_bson_docs(body: &[u8]) -> BoxResult<Bson> {
+    let mut reader = std::io::Cursor::new(body);
+    let mut docs = Vec::new();
+    while (reader.position() as usize) < body.len() {
+        match Document::from_reader(&mut reader) {
+            Ok(doc) => docs.push(Bson::Document(doc)),
+            Err(e) => return Err(MyError::bad_request(format!("invalid BSON: {}", e)))
+        }
+    }
+    match docs.len() {
+        1 => Ok(docs.remove(0)),
+        _ => Ok(Bson::Array(docs))
+    }
+}
+
+// Whether a body is empty, or only whitespace
+pub fn is_blank(body: &[u8]) -> bool {
+    body.iter().all(u8::is_ascii_whitespace)
+}
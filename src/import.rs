@@ -0,0 +1,234 @@
+use bson::Bson;
+use clap::ArgMatches;
+use mongodb::bson::{doc, document::Document};
+use mongodb::error::ErrorKind;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::dates::parse_timestamp;
+use crate::db::DB;
+use crate::error::MyError;
+use crate::schema::set_path;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Rows inserted at a time
+const BATCH_SIZE: usize = 1000;
+
+// Rejected rows listed in the report, beyond which they are only counted
+const MAX_REPORTED: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    String,
+    Int,
+    Float,
+    Bool,
+    Date,
+}
+
+impl ColumnType {
+    fn name(self) -> &'static str {
+        match self {
+            ColumnType::String => "string",
+            ColumnType::Int => "int",
+            ColumnType::Float => "float",
+            ColumnType::Bool => "bool",
+            ColumnType::Date => "date",
+        }
+    }
+}
+
+// Read a mapping like age:int,price:float,born:date
+pub fn parse_types(spec: &str) -> BoxResult<HashMap<String, ColumnType>> {
+    let mut types = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (column, kind) = match entry.rsplit_once(':') {
+            Some(pair) => pair,
+            None => return Err(MyError::bad_request(format!("column type {} should look like column:type", entry)))
+        };
+        let kind = match kind {
+            "string" => ColumnType::String,
+            "int" => ColumnType::Int,
+            "float" => ColumnType::Float,
+            "bool" => ColumnType::Bool,
+            "date" => ColumnType::Date,
+            other => return Err(MyError::bad_request(format!("unknown column type {}, use string, int, float, bool or date", other)))
+        };
+        types.insert(column.to_owned(), kind);
+    }
+    Ok(types)
+}
+
+// Insert every row of a CSV or TSV with a header row, returning how many rows were inserted and rejected
+pub async fn import(db: &DB, opts: ArgMatches<'_>, collection: &str, data: &str, delimiter: u8, types: &HashMap<String, ColumnType>) -> BoxResult<Document> {
+    // Spreadsheets like to start files with a byte order mark
+    let data = data.strip_prefix('\u{feff}').unwrap_or(data);
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(data.as_bytes());
+
+    let headers = reader.headers()?.clone();
+    if let Some(column) = types.keys().find(|c| !headers.iter().any(|h| h == c.as_str())) {
+        return Err(MyError::bad_request(format!("column {} has a type, but is not in the header", column)))
+    };
+
+    let mut report = Report::default();
+    let mut batch: Vec<(u64, Document)> = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let row = e.position().map(|p| p.line()).unwrap_or_default();
+                report.reject(row, e.to_string());
+                continue
+            }
+        };
+        let row = record.position().map(|p| p.line()).unwrap_or_default();
+
+        match to_document(&headers, &record, types) {
+            Ok(mongodoc) => batch.push((row, mongodoc)),
+            Err(e) => report.reject(row, e)
+        }
+
+        if batch.len() >= BATCH_SIZE {
+            insert_batch(db, opts.clone(), collection, std::mem::take(&mut batch), &mut report).await?;
+        };
+    }
+    insert_batch(db, opts, collection, batch, &mut report).await?;
+
+    log::info!("Imported {} rows into {}.{}, rejecting {}", report.inserted, db.db, collection, report.rejected);
+    Ok(report.into_document())
+}
+
+// Insert a batch, setting aside rows which fail schema validation or are refused by mongodb
+async fn insert_batch(db: &DB, opts: ArgMatches<'_>, collection: &str, mut batch: Vec<(u64, Document)>, report: &mut Report) -> BoxResult<()> {
+    while !batch.is_empty() {
+        let docs: Vec<Document> = batch.iter().map(|(_, d)| d.clone()).collect();
+        let e = match db.insert_many(opts.clone(), collection, docs).await {
+            Ok(ids) => {
+                report.inserted += ids.len();
+                return Ok(())
+            }
+            Err(e) => e
+        };
+
+        // Nothing is inserted when validation fails, so retry without the invalid rows
+        if let Some(MyError::ValidationError(failures)) = e.downcast_ref::<MyError>() {
+            let mut invalid: Vec<usize> = Vec::new();
+            for failure in failures {
+                let index = failure.get_i64("index").unwrap_or_default() as usize;
+                let message = failure.get_array("errors").ok()
+                    .and_then(|errors| errors.first())
+                    .and_then(Bson::as_document)
+                    .map(|error| match error.get_str("path").unwrap_or_default() {
+                        "" => error.get_str("message").unwrap_or_default().to_owned(),
+                        path => format!("{} at {}", error.get_str("message").unwrap_or_default(), path)
+                    })
+                    .unwrap_or_else(|| "failed schema validation".to_owned());
+                report.reject(batch[index].0, message);
+                invalid.push(index);
+            }
+            batch = batch.into_iter().enumerate().filter(|(i, _)| !invalid.contains(i)).map(|(_, r)| r).collect();
+            continue
+        };
+
+        // Inserts are ordered, so rows before the failed one were inserted, and the rest are retried
+        if let Some(ErrorKind::BulkWrite(failure)) = e.downcast_ref::<mongodb::error::Error>().map(|e| e.kind.as_ref()) {
+            if let Some(error) = failure.write_errors.as_ref().and_then(|errors| errors.first()) {
+                report.inserted += error.index;
+                report.reject(batch[error.index].0, error.message.clone());
+                batch.drain(..=error.index);
+                continue
+            };
+        };
+
+        return Err(e)
+    }
+    Ok(())
+}
+
+fn to_document(headers: &csv::StringRecord, record: &csv::StringRecord, types: &HashMap<String, ColumnType>) -> Result<Document, String> {
+    let mut mongodoc = Document::new();
+    for (column, value) in headers.iter().zip(record.iter()) {
+        let value = match types.get(column) {
+            Some(kind) => convert(column, value, *kind)?,
+            None => infer(value)
+        };
+        // Dotted columns, like those written by CSV output, become nested documents
+        if let Some(value) = value {
+            set_path(&mut mongodoc, column, value);
+        };
+    }
+    Ok(mongodoc)
+}
+
+fn convert(column: &str, value: &str, kind: ColumnType) -> Result<Option<Bson>, String> {
+    if value.is_empty() && kind != ColumnType::String {
+        return Ok(None)
+    };
+    let converted = match kind {
+        ColumnType::String => Some(Bson::String(value.to_owned())),
+        ColumnType::Int => value.parse::<i64>().ok().map(Bson::Int64),
+        ColumnType::Float => value.parse::<f64>().ok().map(Bson::Double),
+        ColumnType::Bool => parse_bool(value).map(Bson::Boolean),
+        ColumnType::Date => parse_timestamp(value).map(|date| Bson::DateTime(date.into())),
+    };
+    match converted {
+        Some(converted) => Ok(Some(converted)),
+        None => Err(format!("column {}: {:?} is not a valid {}", column, value, kind.name()))
+    }
+}
+
+// Empty cells are left out, and numbers with leading zeros, like zip codes, stay strings
+fn infer(value: &str) -> Option<Bson> {
+    if value.is_empty() {
+        return None
+    };
+    if let Some(b) = parse_bool(value) {
+        return Some(Bson::Boolean(b))
+    };
+    let digits = value.trim_start_matches('-');
+    if digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.") {
+        return Some(Bson::String(value.to_owned()))
+    };
+    if let Ok(i) = value.parse::<i64>() {
+        return Some(Bson::Int64(i))
+    };
+    match value.parse::<f64>() {
+        Ok(f) if f.is_finite() && value.chars().any(|c| c.is_ascii_digit()) => Some(Bson::Double(f)),
+        _ => Some(Bson::String(value.to_owned()))
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    inserted: usize,
+    rejected: usize,
+    errors: Vec<Document>,
+}
+
+impl Report {
+    fn reject(&mut self, row: u64, error: String) {
+        self.rejected += 1;
+        if self.errors.len() < MAX_REPORTED {
+            self.errors.push(doc! { "row": row as i64, "error": error });
+        };
+    }
+
+    fn into_document(self) -> Document {
+        doc! {
+            "inserted": self.inserted as i64,
+            "rejected": self.rejected as i64,
+            "errors": self.errors,
+        }
+    }
+}
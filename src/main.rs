@@ -7,12 +7,27 @@ use hyper::{Body, Request, Server};
 use std::io::Write;
 use std::error::Error;
 
+use config::Config;
 use db::DB;
 //use error::MyError;
 
+mod aggs;
+mod compression;
+mod config;
+mod dates;
 mod db;
 mod error;
+mod format;
+mod import;
+mod params;
+mod provision;
+mod query_string;
+mod retention;
+mod schema;
+mod search;
 mod server;
+mod table;
+mod webhooks;
 
 type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
@@ -59,6 +74,32 @@ async fn main() -> Result<()> {
                 .help("Only access database read-only")
                 .required(false)
         )
+        .arg(
+            Arg::with_name("admin_token")
+                .short("a")
+                .long("admin-token")
+                .required(false)
+                .value_name("TOKEN")
+                .env("ADMIN_TOKEN")
+                .help("Bearer token required for admin actions")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("check_only")
+                .long("check-only")
+                .help("Print how the database differs from the config, then exit")
+                .required(false)
+        )
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .required(false)
+                .value_name("FILE")
+                .env("CONFIG_FILE")
+                .help("YAML file with per-collection settings")
+                .takes_value(true),
+        )
         .get_matches();
 
     // Initialize log Builder
@@ -85,7 +126,45 @@ async fn main() -> Result<()> {
         8080
     });
 
-    let db = DB::init(&url, &db).await?;
+    let config = match opts.value_of("config") {
+        Some(path) => Config::load(path)?,
+        None => Config::default()
+    };
+
+    let db = DB::init(&url, &db, config).await?;
+
+    // Bring collections in line with the config
+    if !db.config.provisioned().is_empty() {
+        let changes = provision::plan(&db).await?;
+        if opts.is_present("check_only") {
+            for change in changes.iter() {
+                println!("{}", change);
+            }
+            println!("{} differences found", changes.len());
+            std::process::exit(if changes.is_empty() { 0 } else { 1 });
+        };
+        match opts.is_present("readonly") {
+            true => changes.iter().for_each(|change| log::warn!("Not provisioning in readonly mode: {}", change)),
+            false => {
+                let applied = provision::apply(&db, opts.clone(), &changes).await?;
+                log::info!("Applied {} of {} differences from config", applied, changes.len());
+            }
+        };
+    } else if opts.is_present("check_only") {
+        println!("No collections are provisioned in the config");
+        return Ok(())
+    };
+
+    // Deliver any queued webhooks in the background
+    if db.config.has_webhooks() && !opts.is_present("readonly") {
+        tokio::spawn(webhooks::worker(db.clone()));
+    };
+
+    // Prune collections with a retention policy in the background
+    if !db.config.retained().is_empty() && !opts.is_present("readonly") {
+        tokio::spawn(retention::worker(db.clone()));
+    };
+
     let addr = ([0, 0, 0, 0], port).into();
     let service = make_service_fn(move |_| {
         let opts = opts.clone();
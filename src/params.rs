@@ -0,0 +1,159 @@
+use bson::Bson;
+use mongodb::bson::{doc, document::Document};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use crate::error::MyError;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Parameters with a meaning of their own, every other parameter is matched as field=value
+const RESERVED: [&str; 9] = ["filter", "projection", "sort", "limit", "skip", "format", "pretty", "envelope", "include_id"];
+
+// The same cap as finds with a POST body
+pub const MAX_LIMIT: i64 = 100;
+
+// A find or count request, read from url query parameters
+#[derive(Debug, Default)]
+pub struct QueryRequest {
+    pub filter: Document,
+    pub projection: Option<Document>,
+    pub sort: Option<Document>,
+    pub limit: Option<u64>,
+    pub skip: Option<u64>,
+}
+
+impl QueryRequest {
+    // Whether only a filter was given, so the request means the same as its POST equivalent
+    pub fn is_plain(&self) -> bool {
+        self.sort.is_none() && self.limit.is_none() && self.skip.is_none()
+    }
+}
+
+pub fn parse(params: &HashMap<String, String>) -> BoxResult<QueryRequest> {
+    let mut filter = match params.get("filter") {
+        Some(filter) => json_param("filter", filter)?,
+        None => doc! {}
+    };
+
+    // Sort parameters so the filter is built the same way for the same url
+    let mut fields: Vec<(&String, &String)> = params.iter().filter(|(k, _)| !RESERVED.contains(&k.as_str())).collect();
+    fields.sort();
+    for (field, value) in fields {
+        filter.insert(field.as_str(), coerce(value));
+    }
+
+    // Projections and sorts are JSON documents, or comma separated fields with - to exclude or descend
+    let projection = match params.get("projection") {
+        Some(projection) if projection.trim_start().starts_with('{') => Some(json_param("projection", projection)?),
+        Some(projection) => Some(field_list(projection, 0)),
+        None => None
+    };
+    let sort = match params.get("sort") {
+        Some(sort) if sort.trim_start().starts_with('{') => Some(json_param("sort", sort)?),
+        Some(sort) => Some(field_list(sort, -1)),
+        None => None
+    };
+
+    Ok(QueryRequest {
+        filter,
+        projection,
+        sort,
+        limit: number_param(params, "limit")?,
+        skip: number_param(params, "skip")?,
+    })
+}
+
+// Values look like JSON scalars where they can, and are strings otherwise, quote them to force a string
+fn coerce(value: &str) -> Bson {
+    match value {
+        "true" => Bson::Boolean(true),
+        "false" => Bson::Boolean(false),
+        "null" => Bson::Null,
+        _ if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') => {
+            Bson::String(value[1..value.len() - 1].to_owned())
+        }
+        _ => match value.parse::<i64>() {
+            Ok(i) => Bson::Int64(i),
+            Err(_) => match value.parse::<f64>() {
+                Ok(f) if f.is_finite() => Bson::Double(f),
+                _ => Bson::String(value.to_owned())
+            }
+        }
+    }
+}
+
+fn field_list(fields: &str, negated: i32) -> Document {
+    let mut result = Document::new();
+    for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        match field.strip_prefix('-') {
+            Some(field) => result.insert(field, negated),
+            None => result.insert(field, 1),
+        };
+    }
+    result
+}
+
+fn json_param(name: &str, value: &str) -> BoxResult<Document> {
+    let json: serde_json::Value = match serde_json::from_str(value) {
+        Ok(json) => json,
+        Err(e) => return Err(MyError::bad_request(format!("{} is not valid JSON: {}", name, e)))
+    };
+    match Bson::try_from(json) {
+        Ok(Bson::Document(doc)) => Ok(doc),
+        _ => Err(MyError::bad_request(format!("{} must be a JSON object", name)))
+    }
+}
+
+fn number_param<T: std::str::FromStr>(params: &HashMap<String, String>, name: &str) -> BoxResult<Option<T>> {
+    match params.get(name) {
+        Some(value) => match value.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) if value.trim_start().starts_with('-') => Err(MyError::bad_request(format!("{} cannot be negative", name))),
+            Err(_) => Err(MyError::bad_request(format!("{} must be a whole number", name)))
+        },
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn values_are_coerced_to_their_type() {
+        assert_eq!(coerce("true"), Bson::Boolean(true));
+        assert_eq!(coerce("false"), Bson::Boolean(false));
+        assert_eq!(coerce("null"), Bson::Null);
+        assert_eq!(coerce("42"), Bson::Int64(42));
+        assert_eq!(coerce("-1.5"), Bson::Double(-1.5));
+        assert_eq!(coerce("word"), Bson::String("word".to_owned()));
+    }
+
+    #[test]
+    fn quoted_values_stay_strings() {
+        assert_eq!(coerce("\"42\""), Bson::String("42".to_owned()));
+        assert_eq!(coerce("\"true\""), Bson::String("true".to_owned()));
+        assert_eq!(coerce("NaN"), Bson::String("NaN".to_owned()));
+    }
+
+    #[test]
+    fn reserved_params_are_not_filters() {
+        let request = parse(&params(&[("status", "active"), ("count", "3"), ("limit", "5"), ("pretty", "true")])).unwrap();
+        assert_eq!(request.filter, doc! { "count": 3_i64, "status": "active" });
+        assert_eq!(request.limit, Some(5));
+    }
+
+    #[test]
+    fn negative_limits_and_skips_are_refused() {
+        assert!(parse(&params(&[("limit", "-5")])).is_err());
+        assert!(parse(&params(&[("skip", "-1")])).is_err());
+        assert!(parse(&params(&[("limit", "ten")])).is_err());
+        assert_eq!(parse(&params(&[("limit", "0")])).unwrap().limit, Some(0));
+    }
+}
@@ -0,0 +1,246 @@
+use bson::Bson;
+use clap::ArgMatches;
+use mongodb::bson::{doc, document::Document};
+use std::error::Error;
+use std::fmt;
+
+use crate::config::CollectionConfig;
+use crate::db::{index_name, DB};
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Name of the TTL index created for expire_after
+const EXPIRE_INDEX: &str = "_time_expire";
+
+// Options which are not compared when checking existing indexes for drift
+const IGNORED_OPTIONS: [&str; 2] = ["name", "v"];
+
+#[derive(Debug)]
+pub enum Change {
+    CreateCollection { collection: String, options: Document },
+    CreateIndex { collection: String, index: Document },
+    DropIndex { collection: String, name: String },
+    SetValidator { collection: String, settings: Document },
+    // Differences which are reported, but never applied automatically
+    Drift { collection: String, message: String },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::CreateCollection { collection, options } => write!(f, "+ {}: create collection {}", collection, options),
+            Change::CreateIndex { collection, index } => write!(f, "+ {}: create index {}", collection, index),
+            Change::DropIndex { collection, name } => write!(f, "- {}: drop index {}", collection, name),
+            Change::SetValidator { collection, settings } => write!(f, "~ {}: set validator {}", collection, settings),
+            Change::Drift { collection, message } => write!(f, "! {}: {}", collection, message),
+        }
+    }
+}
+
+// Compare the collections in the config with the database
+pub async fn plan(db: &DB) -> BoxResult<Vec<Change>> {
+    let existing = db.collections().await?;
+    let mut changes = Vec::new();
+
+    for (collection, config) in db.config.provisioned() {
+        log::debug!("Checking {}.{} against config", db.db, collection);
+
+        match existing.contains(collection) {
+            true => {
+                plan_validator(db, collection, config, &mut changes).await?;
+                plan_capped(db, collection, config, &mut changes).await?;
+                let indexes = db.get_indexes(collection).await?.get_array("firstBatch")?.clone();
+                plan_indexes(collection, config, indexes, &mut changes);
+            }
+            false => {
+                let mut options = config.validator_settings().unwrap_or_default();
+                if let Some(capped) = &config.capped {
+                    options.insert("capped", true);
+                    options.insert("size", capped.size);
+                    if let Some(max) = capped.max {
+                        options.insert("max", max);
+                    };
+                };
+                changes.push(Change::CreateCollection { collection: collection.clone(), options });
+                plan_indexes(collection, config, Vec::new(), &mut changes);
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+// Apply everything but drift, returning how many changes were made
+pub async fn apply(db: &DB, opts: ArgMatches<'_>, changes: &[Change]) -> BoxResult<usize> {
+    let mut applied = 0;
+    for change in changes {
+        match change {
+            Change::CreateCollection { collection, options } => {
+                db.create_collection(opts.clone(), collection, options.clone()).await?;
+            }
+            Change::CreateIndex { collection, index } => {
+                db.create_indexes(opts.clone(), collection, vec![index.clone()]).await?;
+            }
+            Change::DropIndex { collection, name } => {
+                db.drop_index(opts.clone(), collection, name).await?;
+            }
+            Change::SetValidator { collection, settings } => {
+                db.set_validator(opts.clone(), collection, settings.clone()).await?;
+            }
+            Change::Drift { .. } => {
+                log::warn!("Config drift: {}", change);
+                continue
+            }
+        };
+        log::info!("Provisioned: {}", change);
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+async fn plan_validator(db: &DB, collection: &str, config: &CollectionConfig, changes: &mut Vec<Change>) -> BoxResult<()> {
+    let settings = match config.validator_settings() {
+        Some(settings) => settings,
+        None => return Ok(())
+    };
+
+    let current = db.get_validator(collection).await?;
+    let differs = settings.iter().any(|(k, v)| !same(current.get(k), Some(v)));
+    if differs {
+        changes.push(Change::SetValidator { collection: collection.to_owned(), settings });
+    };
+    Ok(())
+}
+
+// Collections cannot be capped in place without rewriting them, so only report the difference
+async fn plan_capped(db: &DB, collection: &str, config: &CollectionConfig, changes: &mut Vec<Change>) -> BoxResult<()> {
+    let database = db.client.database(&db.db);
+    let stats = database.run_command(doc! { "collStats": collection }, None).await?;
+    let capped = stats.get_bool("capped").unwrap_or(false);
+
+    match (&config.capped, capped) {
+        (Some(_), false) => changes.push(Change::Drift {
+            collection: collection.to_owned(),
+            message: "collection should be capped, but is not".to_owned()
+        }),
+        (None, true) => changes.push(Change::Drift {
+            collection: collection.to_owned(),
+            message: "collection is capped, but the config does not say so".to_owned()
+        }),
+        _ => ()
+    };
+    Ok(())
+}
+
+fn plan_indexes(collection: &str, config: &CollectionConfig, existing: Vec<Bson>, changes: &mut Vec<Change>) {
+    let existing: Vec<Document> = existing.into_iter().filter_map(|i| i.as_document().cloned()).collect();
+    let desired = desired_indexes(config);
+
+    for index in desired.iter() {
+        let name = index.get_str("name").unwrap_or_default();
+        match existing.iter().find(|i| i.get_str("name").unwrap_or_default() == name) {
+            Some(current) => {
+                let differs: Vec<&String> = index
+                    .keys()
+                    .filter(|k| !IGNORED_OPTIONS.contains(&k.as_str()))
+                    .filter(|k| !same(current.get(k), index.get(k)))
+                    .collect();
+                if !differs.is_empty() {
+                    changes.push(Change::Drift {
+                        collection: collection.to_owned(),
+                        message: format!("index {} differs from config in {:?}, drop it to recreate", name, differs)
+                    });
+                };
+            }
+            None => changes.push(Change::CreateIndex { collection: collection.to_owned(), index: index.clone() })
+        }
+    }
+
+    for current in existing.iter() {
+        let name = current.get_str("name").unwrap_or_default();
+        if name == "_id_" || desired.iter().any(|i| i.get_str("name").unwrap_or_default() == name) {
+            continue
+        };
+        match config.drop_unknown_indexes {
+            true => changes.push(Change::DropIndex { collection: collection.to_owned(), name: name.to_owned() }),
+            false => changes.push(Change::Drift {
+                collection: collection.to_owned(),
+                message: format!("index {} is not in the config", name)
+            })
+        }
+    }
+}
+
+fn desired_indexes(config: &CollectionConfig) -> Vec<Document> {
+    let mut desired = config.indexes.clone();
+    if let Some(seconds) = config.expire_after {
+        desired.push(doc! { "key": { "_time": 1 }, "name": EXPIRE_INDEX, "expireAfterSeconds": seconds });
+    };
+
+    for index in desired.iter_mut() {
+        if !index.contains_key("name") {
+            let name = index_name(&index.get_document("key").cloned().unwrap_or_default());
+            index.insert("name", name);
+        };
+    }
+    desired
+}
+
+// Compare values without caring whether numbers are stored as int32, int64 or double.
+// Keys are compared in order, as the order of compound index keys matters.
+fn same(a: Option<&Bson>, b: Option<&Bson>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => same_value(a, b),
+        (a, b) => a.is_none() && b.is_none()
+    }
+}
+
+fn same_value(a: &Bson, b: &Bson) -> bool {
+    match (a, b) {
+        (Bson::Document(a), Bson::Document(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|((ka, va), (kb, vb))| ka == kb && same_value(va, vb))
+        }
+        (Bson::Array(a), Bson::Array(b)) => a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b)),
+        (a, b) => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => a == b
+        }
+    }
+}
+
+fn number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(d) => Some(*d),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same_doc(a: Document, b: Document) -> bool {
+        same_value(&Bson::Document(a), &Bson::Document(b))
+    }
+
+    #[test]
+    fn numbers_match_whatever_their_type() {
+        assert!(same_doc(doc! { "a": 1, "b": -1_i64 }, doc! { "a": 1.0, "b": -1 }));
+        assert!(!same_doc(doc! { "a": 1 }, doc! { "a": -1 }));
+    }
+
+    #[test]
+    fn key_order_matters() {
+        assert!(same_doc(doc! { "a": 1, "b": 1 }, doc! { "a": 1, "b": 1 }));
+        assert!(!same_doc(doc! { "a": 1, "b": 1 }, doc! { "b": 1, "a": 1 }));
+        assert!(!same_doc(doc! { "a": 1 }, doc! { "a": 1, "b": 1 }));
+    }
+
+    #[test]
+    fn missing_values_only_match_missing_values() {
+        assert!(same(None, None));
+        assert!(!same(Some(&Bson::Int32(1)), None));
+    }
+}
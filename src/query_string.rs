@@ -0,0 +1,447 @@
+use bson::Bson;
+use mongodb::bson::{doc, document::Document};
+use std::error::Error;
+
+use crate::error::MyError;
+use crate::search::{escape, parse_date, regex};
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Parse a Lucene style query string, like title:foo AND _time:>2021-01-01, into a mongodb filter
+pub fn parse(query: &str, default_field: Option<&str>, default_and: bool) -> BoxResult<Document> {
+    let mut parser = Parser {
+        chars: query.chars().collect(),
+        pos: 0,
+        default_and,
+    };
+
+    let filter = parser.query(default_field)?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(filter),
+        Some(c) => Err(parser.error(&format!("unexpected '{}'", c)))
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // Whether terms without an operator between them must all match
+    default_and: bool,
+}
+
+// Whether a clause must, must not or may match, as Lucene's +, - and plain terms
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Occur {
+    Must,
+    MustNot,
+    Should,
+}
+
+impl Parser {
+    // Required and prohibited clauses always apply, and as in Lucene, optional clauses
+    // only need to match when no clause is required
+    fn query(&mut self, field: Option<&str>) -> BoxResult<Document> {
+        let mut must = Vec::new();
+        let mut must_not = Vec::new();
+        let mut should = Vec::new();
+
+        let mut first = true;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(')') if !first => break,
+                _ => ()
+            };
+
+            // Terms without an operator between them are optional, as in Lucene
+            let after_or = !first && (self.keyword("OR") || self.symbol("||"));
+            first = false;
+
+            match self.and(field)? {
+                (Occur::Must, clause) => must.push(clause),
+                // a OR NOT b means either of them
+                (Occur::MustNot, clause) if after_or => should.push(doc! { "$nor": [clause] }),
+                (Occur::MustNot, clause) => must_not.push(clause),
+                (Occur::Should, clause) => should.push(clause),
+            }
+        }
+
+        let must_count = must.len();
+        let mut clauses = must;
+        if !must_not.is_empty() {
+            clauses.push(doc! { "$nor": must_not });
+        };
+        if !should.is_empty() && must_count == 0 {
+            clauses.push(combine("$or", should));
+        };
+        Ok(combine("$and", clauses))
+    }
+
+    // Clauses joined by AND are all required, and a lone clause keeps its own prefix
+    fn and(&mut self, field: Option<&str>) -> BoxResult<(Occur, Document)> {
+        let mut clauses = vec![self.unary(field)?];
+        loop {
+            self.skip_whitespace();
+            if self.keyword("AND") || self.symbol("&&") {
+                clauses.push(self.unary(field)?);
+                continue
+            };
+            match self.peek() {
+                None | Some(')') => break,
+                _ if self.default_and && !self.peek_keyword("OR") && !self.peek_symbol("||") => clauses.push(self.unary(field)?),
+                _ => break
+            }
+        }
+        match clauses.len() {
+            1 => Ok(clauses.remove(0)),
+            _ => Ok((Occur::Must, combine("$and", clauses.into_iter().map(constraint).collect())))
+        }
+    }
+
+    fn unary(&mut self, field: Option<&str>) -> BoxResult<(Occur, Document)> {
+        self.skip_whitespace();
+        if self.keyword("NOT") || self.symbol("!") || self.symbol("-") {
+            let negated = self.unary(field)?;
+            return Ok((Occur::MustNot, constraint(negated)))
+        };
+        if self.symbol("+") {
+            let required = self.unary(field)?;
+            return Ok((Occur::Must, constraint(required)))
+        };
+        Ok((Occur::Should, self.primary(field)?))
+    }
+
+    fn primary(&mut self, field: Option<&str>) -> BoxResult<Document> {
+        self.skip_whitespace();
+        if self.symbol("(") {
+            let group = self.query(field)?;
+            self.expect(')')?;
+            return Ok(group)
+        };
+
+        match self.field_name() {
+            Some(name) => self.value(Some(&name)),
+            None => self.value(field)
+        }
+    }
+
+    // Read "field:" if present, leaving the position untouched otherwise
+    fn field_name(&mut self) -> Option<String> {
+        let start = self.pos;
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                ':' if !name.is_empty() => {
+                    self.pos += 1;
+                    return Some(name)
+                }
+                c if c.is_whitespace() || "():\"[]{}".contains(c) => break,
+                c => {
+                    name.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.pos = start;
+        None
+    }
+
+    fn value(&mut self, field: Option<&str>) -> BoxResult<Document> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let group = self.query(field)?;
+                self.expect(')')?;
+                Ok(group)
+            }
+            Some('"') => {
+                let phrase = self.phrase()?;
+                Ok(regex(self.require_field(field)?, escape(&phrase), true))
+            }
+            Some('[') | Some('{') => self.range(self.require_field(field)?),
+            Some('>') | Some('<') => {
+                let field = self.require_field(field)?;
+                let operator = match (self.next(), self.symbol("=")) {
+                    (Some('>'), false) => "$gt",
+                    (Some('>'), true) => "$gte",
+                    (_, false) => "$lt",
+                    (_, true) => "$lte",
+                };
+                let bound = self.token();
+                match bound.is_empty() {
+                    true => Err(self.error("expected a value to compare against")),
+                    false => Ok(doc! { field: { operator: range_value(&bound) } })
+                }
+            }
+            _ => {
+                let token = self.token();
+                if token.is_empty() {
+                    return Err(self.error("expected a term"))
+                };
+                let field = self.require_field(field)?;
+                term(field, &token)
+            }
+        }
+    }
+
+    // [from TO to] is inclusive and {from TO to} exclusive, with * for an open end
+    fn range(&mut self, field: &str) -> BoxResult<Document> {
+        let lower_inclusive = self.next() == Some('[');
+        self.skip_whitespace();
+        let lower = self.range_token();
+        self.skip_whitespace();
+        if !self.keyword("TO") {
+            return Err(self.error("expected TO in range"))
+        };
+        self.skip_whitespace();
+        let upper = self.range_token();
+        self.skip_whitespace();
+        let upper_inclusive = match self.next() {
+            Some(']') => true,
+            Some('}') => false,
+            _ => return Err(self.error("expected ] or } to close range"))
+        };
+
+        let mut bounds = Document::new();
+        if lower != "*" {
+            bounds.insert(if lower_inclusive { "$gte" } else { "$gt" }, range_value(&lower));
+        };
+        if upper != "*" {
+            bounds.insert(if upper_inclusive { "$lte" } else { "$lt" }, range_value(&upper));
+        };
+        match bounds.is_empty() {
+            true => Ok(doc! { field: { "$exists": true } }),
+            false => Ok(doc! { field: bounds })
+        }
+    }
+
+    fn phrase(&mut self) -> BoxResult<String> {
+        self.pos += 1;
+        let mut phrase = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(phrase),
+                Some('\\') => {
+                    if let Some(c) = self.next() {
+                        phrase.push(c);
+                    }
+                }
+                Some(c) => phrase.push(c),
+                None => return Err(self.error("unterminated phrase"))
+            }
+        }
+    }
+
+    // Read a term, honoring backslash escapes
+    fn token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                    if let Some(c) = self.next() {
+                        token.push(c);
+                    }
+                }
+                c if c.is_whitespace() || c == ')' => break,
+                c => {
+                    token.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        token
+    }
+
+    fn range_token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ']' || c == '}' {
+                break
+            };
+            token.push(c);
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn require_field<'a>(&self, field: Option<&'a str>) -> BoxResult<&'a str> {
+        field.ok_or_else(|| self.error("terms need a field, like title:foo, or a default field passed as df"))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek_keyword(keyword) {
+            true => {
+                self.pos += keyword.len();
+                true
+            }
+            false => false
+        }
+    }
+
+    // Keywords must be followed by whitespace or a group, so that ORDER is not read as OR
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        let end = self.pos + keyword.len();
+        self.peek_symbol(keyword) && match self.chars.get(end) {
+            Some(c) => c.is_whitespace() || *c == '(' || *c == '"',
+            None => false
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.peek_symbol(symbol) {
+            true => {
+                self.pos += symbol.chars().count();
+                true
+            }
+            false => false
+        }
+    }
+
+    fn peek_symbol(&self, symbol: &str) -> bool {
+        symbol.chars().enumerate().all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn expect(&mut self, c: char) -> BoxResult<()> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(found) if found == c => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(char::is_whitespace).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn error(&self, message: &str) -> Box<dyn Error + Send + Sync> {
+        MyError::bad_request(format!("{} at position {} of query string", message, self.pos))
+    }
+}
+
+fn term(field: &str, token: &str) -> BoxResult<Document> {
+    if field == "_exists_" {
+        return Ok(doc! { token: { "$exists": true } })
+    };
+    if token == "*" {
+        return Ok(doc! { field: { "$exists": true } })
+    };
+
+    // Words match case insensitively, while numbers and booleans also match their typed value
+    let typed = match token {
+        "true" => Some(Bson::Boolean(true)),
+        "false" => Some(Bson::Boolean(false)),
+        _ => token.parse::<i64>().map(Bson::Int64)
+            .or_else(|_| token.parse::<f64>().map(Bson::Double))
+            .ok()
+    };
+    if let Some(typed) = typed {
+        return Ok(doc! { field: { "$in": [typed, token] } })
+    };
+
+    match token.contains('*') || token.contains('?') {
+        true => {
+            let mut pattern = String::from("\\b");
+            for c in token.chars() {
+                match c {
+                    '*' => pattern.push_str("\\w*"),
+                    '?' => pattern.push_str("\\w"),
+                    c => pattern.push_str(&escape(&c.to_string()))
+                }
+            }
+            pattern.push_str("\\b");
+            Ok(regex(field, pattern, true))
+        }
+        false => Ok(regex(field, format!("\\b{}\\b", escape(token)), true))
+    }
+}
+
+// Dates and numbers are compared by value, anything else as a string
+fn range_value(value: &str) -> Bson {
+    if let Some(date) = parse_date(value) {
+        return Bson::DateTime(date.into())
+    };
+    match value.parse::<i64>() {
+        Ok(i) => Bson::Int64(i),
+        Err(_) => match value.parse::<f64>() {
+            Ok(f) => Bson::Double(f),
+            Err(_) => Bson::String(value.to_owned())
+        }
+    }
+}
+
+// The filter a clause adds on its own
+fn constraint((occur, clause): (Occur, Document)) -> Document {
+    match occur {
+        Occur::MustNot => doc! { "$nor": [clause] },
+        _ => clause
+    }
+}
+
+fn combine(operator: &str, mut clauses: Vec<Document>) -> Document {
+    match clauses.len() {
+        1 => clauses.remove(0),
+        _ => doc! { operator: clauses }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(field: &str, token: &str) -> Document {
+        term(field, token).unwrap()
+    }
+
+    fn not(clause: Document) -> Document {
+        doc! { "$nor": [clause] }
+    }
+
+    #[test]
+    fn plain_terms_are_optional() {
+        assert_eq!(parse("title:foo body:bar", None, false).unwrap(), doc! { "$or": [word("title", "foo"), word("body", "bar")] });
+        assert_eq!(parse("title:foo body:bar", None, true).unwrap(), doc! { "$and": [word("title", "foo"), word("body", "bar")] });
+    }
+
+    #[test]
+    fn negated_clauses_are_excluded() {
+        assert_eq!(parse("title:foo -title:draft", None, false).unwrap(), doc! { "$and": [not(word("title", "draft")), word("title", "foo")] });
+        assert_eq!(parse("title:foo NOT title:draft", None, false).unwrap(), doc! { "$and": [not(word("title", "draft")), word("title", "foo")] });
+        assert_eq!(parse("-title:draft", None, false).unwrap(), not(word("title", "draft")));
+    }
+
+    #[test]
+    fn required_clauses_must_match() {
+        assert_eq!(parse("+title:foo body:bar", None, false).unwrap(), word("title", "foo"));
+        assert_eq!(parse("+title:foo body:bar", None, true).unwrap(), doc! { "$and": [word("title", "foo"), word("body", "bar")] });
+        assert_eq!(
+            parse("+title:foo -body:bar tag:x tag:y", None, false).unwrap(),
+            doc! { "$and": [word("title", "foo"), not(word("body", "bar"))] }
+        );
+    }
+
+    #[test]
+    fn or_not_means_either() {
+        assert_eq!(parse("title:foo OR NOT title:bar", None, false).unwrap(), doc! { "$or": [word("title", "foo"), not(word("title", "bar"))] });
+    }
+
+    #[test]
+    fn terms_need_a_field() {
+        assert!(parse("foo", None, false).is_err());
+        assert_eq!(parse("foo", Some("title"), false).unwrap(), word("title", "foo"));
+    }
+}
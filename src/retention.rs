@@ -0,0 +1,118 @@
+use chrono::prelude::*;
+use futures::StreamExt;
+use mongodb::bson::{doc, document::Document};
+use mongodb::options::{FindOptions, UpdateOptions};
+use std::error::Error;
+use std::time::Duration;
+
+use crate::config::RetentionConfig;
+use crate::db::DB;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Collection holding the result of the last prune for each collection
+pub const STATUS_COLLECTION: &str = "_json_bucket_retention";
+
+// Most documents deleted at once when enforcing max_docs
+const BATCH_SIZE: i64 = 10000;
+
+// Background task pruning collections with a retention policy
+pub async fn worker(db: DB) {
+    let interval = Duration::from_secs(db.config.prune_interval.max(1));
+
+    loop {
+        for (collection, policy) in db.config.retained() {
+            let started = Utc::now();
+            let result = prune(&db, collection, policy).await;
+
+            let mut status = doc! { "last_run": started, "took_ms": (Utc::now() - started).num_milliseconds() };
+            match result {
+                Ok(deleted) => {
+                    log::debug!("Pruned {} docs from {}.{}", deleted, db.db, collection);
+                    status.insert("deleted", deleted);
+                }
+                Err(e) => {
+                    log::error!("Error pruning {}.{}: {}", db.db, collection, e);
+                    status.insert("error", e.to_string());
+                }
+            };
+
+            if let Err(e) = save_status(&db, collection, status).await {
+                log::error!("Error saving retention status for {}: {}", collection, e);
+            }
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn prune(db: &DB, collection: &str, policy: &RetentionConfig) -> BoxResult<i64> {
+    let coll = db.client.database(&db.db).collection::<Document>(collection);
+    let mut deleted = 0;
+
+    if let Some(max_age) = policy.max_age {
+        // An age dates cannot reach fails this collection's prune, rather than panicking the worker
+        let cutoff = chrono::Duration::try_seconds(max_age)
+            .filter(|_| max_age >= 0)
+            .and_then(|age| Utc::now().checked_sub_signed(age))
+            .ok_or_else(|| format!("max_age {} is out of range", max_age))?;
+        let result = coll.delete_many(doc! { "_time": { "$lt": cutoff } }, None).await?;
+        deleted += result.deleted_count as i64;
+    };
+
+    if let Some(max_docs) = policy.max_docs {
+        // Find the ids of the oldest docs beyond the limit, a batch at a time
+        let find_options = FindOptions::builder()
+            .sort(doc! { "_time": -1, "_id": -1 })
+            .projection(doc! { "_id": 1 })
+            .skip(max_docs.max(0) as u64)
+            .limit(BATCH_SIZE)
+            .build();
+
+        let mut cursor = coll.find(None, find_options).await?;
+        let mut ids = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            if let Some(id) = doc?.get("_id") {
+                ids.push(id.clone());
+            }
+        }
+
+        if !ids.is_empty() {
+            let result = coll.delete_many(doc! { "_id": { "$in": ids } }, None).await?;
+            deleted += result.deleted_count as i64;
+        };
+    };
+
+    Ok(deleted)
+}
+
+async fn save_status(db: &DB, collection: &str, status: Document) -> BoxResult<()> {
+    let update_options = UpdateOptions::builder()
+        .upsert(true)
+        .build();
+
+    let statuses = db.client.database(&db.db).collection::<Document>(STATUS_COLLECTION);
+    statuses.update_one(doc! { "_id": collection }, doc! { "$set": status }, update_options).await?;
+    Ok(())
+}
+
+// The configured policies, along with the result of their last prune
+pub async fn status(db: &DB) -> BoxResult<Vec<Document>> {
+    let statuses = db.client.database(&db.db).collection::<Document>(STATUS_COLLECTION);
+
+    let mut result = Vec::new();
+    for (collection, policy) in db.config.retained() {
+        let mut last_prune = statuses.find_one(doc! { "_id": collection }, None).await?;
+        if let Some(prune) = last_prune.as_mut() {
+            prune.remove("_id");
+        };
+
+        result.push(doc! {
+            "collection": collection,
+            "max_age": policy.max_age,
+            "max_docs": policy.max_docs,
+            "last_prune": last_prune
+        });
+    }
+    Ok(result)
+}
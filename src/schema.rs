@@ -0,0 +1,146 @@
+use bson::Bson;
+use jsonschema::JSONSchema;
+use mongodb::bson::{doc, document::Document};
+use serde_json::Value;
+use std::error::Error;
+
+use crate::db::DB;
+use crate::error::MyError;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Collection holding schemas set through the api, which take precedence over the config file
+pub const SCHEMA_COLLECTION: &str = "_json_bucket_schemas";
+
+// Fields managed by json-bucket and mongodb, which schemas do not need to describe
+const IGNORED_FIELDS: [&str; 2] = ["_id", "_time"];
+
+pub fn compile(schema: &Value) -> BoxResult<JSONSchema> {
+    match JSONSchema::compile(schema) {
+        Ok(compiled) => Ok(compiled),
+        Err(e) => Err(Box::new(MyError::InvalidSchema(e.to_string())))
+    }
+}
+
+// Validate documents about to be inserted
+pub async fn validate(db: &DB, collection: &str, docs: &[Document]) -> BoxResult<()> {
+    match db.get_schema(collection).await? {
+        Some(schema) => check(&schema, docs),
+        None => Ok(())
+    }
+}
+
+// Validate the document as it will look after the update is applied
+pub async fn validate_update(db: &DB, collection: &str, filter: &Document, update: &Document) -> BoxResult<()> {
+    let schema = match db.get_schema(collection).await? {
+        Some(schema) => schema,
+        None => return Ok(())
+    };
+
+    // Replacement documents are validated as they are
+    if !update.keys().any(|k| k.starts_with('$')) {
+        return check(&schema, std::slice::from_ref(update))
+    };
+
+    let coll = db.client.database(&db.db).collection::<Document>(collection);
+    let image = match coll.find_one(filter.clone(), None).await? {
+        Some(existing) => apply_update(existing, update, false),
+        None => apply_update(equality_fields(filter), update, true)
+    };
+
+    check(&schema, &[image])
+}
+
+fn check(schema: &Value, docs: &[Document]) -> BoxResult<()> {
+    let compiled = compile(schema)?;
+
+    let mut failures = Vec::new();
+    for (index, mongodoc) in docs.iter().enumerate() {
+        let instance = to_instance(mongodoc);
+        let errors: Vec<Document> = match compiled.validate(&instance) {
+            Ok(_) => continue,
+            Err(errors) => errors
+                .map(|e| doc! { "path": e.instance_path.to_string(), "message": e.to_string() })
+                .collect()
+        };
+        failures.push(doc! { "index": index as i64, "errors": errors });
+    }
+
+    match failures.is_empty() {
+        true => Ok(()),
+        false => {
+            log::debug!("{} documents failed schema validation", failures.len());
+            Err(Box::new(MyError::ValidationError(failures)))
+        }
+    }
+}
+
+fn to_instance(mongodoc: &Document) -> Value {
+    let mut instance = mongodoc.clone();
+    for field in IGNORED_FIELDS.iter() {
+        instance.remove(field);
+    }
+    Bson::Document(instance).into_relaxed_extjson()
+}
+
+// The fields an upsert copies from its filter into the new document
+fn equality_fields(filter: &Document) -> Document {
+    let mut base = Document::new();
+    for (key, value) in filter {
+        if key.starts_with('$') {
+            continue
+        };
+        match value {
+            Bson::Document(d) if d.keys().any(|k| k.starts_with('$')) => continue,
+            _ => set_path(&mut base, key, value.clone())
+        }
+    }
+    base
+}
+
+// Apply the $set, $setOnInsert and $unset operators of an update
+fn apply_update(mut base: Document, update: &Document, upsert: bool) -> Document {
+    for (operator, fields) in update {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            _ => continue
+        };
+        match operator.as_str() {
+            "$set" => fields.iter().for_each(|(k, v)| set_path(&mut base, k, v.clone())),
+            "$setOnInsert" if upsert => fields.iter().for_each(|(k, v)| set_path(&mut base, k, v.clone())),
+            "$unset" => fields.keys().for_each(|k| unset_path(&mut base, k)),
+            _ => continue
+        }
+    }
+    base
+}
+
+// Set a dotted path, creating documents along the way
+pub fn set_path(mongodoc: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if !matches!(mongodoc.get(head), Some(Bson::Document(_))) {
+                mongodoc.insert(head, Document::new());
+            };
+            if let Some(Bson::Document(child)) = mongodoc.get_mut(head) {
+                set_path(child, rest, value)
+            }
+        }
+        None => {
+            mongodoc.insert(path, value);
+        }
+    }
+}
+
+fn unset_path(mongodoc: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Bson::Document(child)) = mongodoc.get_mut(head) {
+                unset_path(child, rest)
+            }
+        }
+        None => {
+            mongodoc.remove(path);
+        }
+    }
+}
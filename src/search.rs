@@ -0,0 +1,470 @@
+use bson::Bson;
+use chrono::prelude::*;
+use mongodb::bson::{doc, document::Document};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use crate::aggs::{self, Aggregation};
+use crate::dates::parse_timestamp;
+use crate::db::id_to_string;
+use crate::error::MyError;
+use crate::query_string;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+const DEFAULT_SIZE: i64 = 10;
+const MAX_SIZE: i64 = 10000;
+
+// An Elasticsearch search request, translated for mongodb
+#[derive(Debug, Default)]
+pub struct SearchRequest {
+    pub filter: Document,
+    pub from: u64,
+    pub size: i64,
+    pub sort: Option<Document>,
+    pub projection: Option<Document>,
+    pub aggs: Vec<Aggregation>,
+}
+
+pub fn parse(body: &Value) -> BoxResult<SearchRequest> {
+    let filter = match body.get("query") {
+        Some(query) => translate(query)?,
+        None => doc! {}
+    };
+
+    let sort = match body.get("sort") {
+        Some(sort) => Some(parse_sort(sort)?),
+        None => None
+    };
+
+    let projection = match body.get("_source") {
+        Some(source) => parse_source(source)?,
+        None => None
+    };
+
+    let aggs = match body.get("aggs").or_else(|| body.get("aggregations")) {
+        Some(aggs) => aggs::parse(aggs)?,
+        None => Vec::new()
+    };
+
+    Ok(SearchRequest {
+        filter,
+        from: body.get("from").and_then(Value::as_u64).unwrap_or(0),
+        size: parse_size(body.get("size").and_then(Value::as_i64))?,
+        sort,
+        projection,
+        aggs,
+    })
+}
+
+// Build a search from url parameters, like Elasticsearch's URI search
+pub fn from_params(params: &HashMap<String, String>) -> BoxResult<SearchRequest> {
+    let default_and = params.get("default_operator").map(|o| o.eq_ignore_ascii_case("and")).unwrap_or(false);
+    let filter = match params.get("q") {
+        Some(q) if !q.trim().is_empty() => query_string::parse(q, params.get("df").map(String::as_str), default_and)?,
+        _ => doc! {}
+    };
+
+    // Sorts are given as field:direction, separated by commas
+    let sort = match params.get("sort") {
+        Some(sort) => {
+            let fields: Vec<Value> = sort
+                .split(',')
+                .map(|entry| match entry.split_once(':') {
+                    Some((field, order)) => serde_json::json!({ field: order }),
+                    None => Value::String(entry.to_owned())
+                })
+                .collect();
+            Some(parse_sort(&Value::Array(fields))?)
+        }
+        None => None
+    };
+
+    let projection = match params.get("_source") {
+        Some(source) => {
+            let fields: Vec<Value> = source.split(',').map(|f| Value::String(f.to_owned())).collect();
+            parse_source(&Value::Array(fields))?
+        }
+        None => None
+    };
+
+    Ok(SearchRequest {
+        filter,
+        from: params.get("from").and_then(|f| f.parse().ok()).unwrap_or(0),
+        size: parse_size(params.get("size").and_then(|s| s.parse().ok()))?,
+        sort,
+        projection,
+        aggs: Vec::new(),
+    })
+}
+
+// A size of 0 only counts hits, which is useful alongside aggregations
+fn parse_size(size: Option<i64>) -> BoxResult<i64> {
+    match size {
+        Some(size) if size < 0 => Err(MyError::bad_request("size cannot be negative")),
+        Some(size) => Ok(size.min(MAX_SIZE)),
+        None => Ok(DEFAULT_SIZE)
+    }
+}
+
+// Translate a query clause into a mongodb filter
+pub fn translate(query: &Value) -> BoxResult<Document> {
+    let (kind, body) = single_entry(query, "query")?;
+
+    match kind.as_str() {
+        "match_all" => Ok(doc! {}),
+        "match" => match_query(body, false),
+        "match_phrase" => match_query(body, true),
+        "term" => {
+            let (field, value) = field_value(body, "value")?;
+            Ok(doc! { field: to_bson(value)? })
+        }
+        "terms" => {
+            let (field, values) = single_entry(body, "terms")?;
+            match values {
+                Value::Array(_) => Ok(doc! { field: { "$in": to_bson(values)? } }),
+                _ => Err(MyError::bad_request("terms expects a list of values"))
+            }
+        }
+        "range" => range_query(body),
+        "exists" => match body.get("field").and_then(Value::as_str) {
+            Some(field) => Ok(doc! { field: { "$exists": true } }),
+            None => Err(MyError::bad_request("exists expects a field"))
+        },
+        "prefix" => {
+            let (field, value) = field_value(body, "value")?;
+            let pattern = format!("^{}", escape(as_str(value, "prefix")?));
+            Ok(regex(&field, pattern, case_insensitive(body, &field)))
+        }
+        "wildcard" => {
+            let (field, value) = field_value(body, "value")?;
+            let pattern = wildcard_pattern(as_str(value, "wildcard")?);
+            Ok(regex(&field, pattern, case_insensitive(body, &field)))
+        }
+        "bool" => bool_query(body),
+        other => Err(MyError::bad_request(format!("{} queries are not supported", other)))
+    }
+}
+
+// Approximate full text matching with case insensitive regexes on whole words
+fn match_query(body: &Value, phrase: bool) -> BoxResult<Document> {
+    let (field, spec) = single_entry(body, "match")?;
+    let (text, operator) = match spec {
+        Value::Object(options) => (
+            options.get("query").ok_or_else(|| MyError::bad_request("match expects a query"))?,
+            options.get("operator").and_then(Value::as_str).unwrap_or("or").to_lowercase()
+        ),
+        text => (text, "or".to_owned())
+    };
+
+    let text = match text {
+        Value::String(text) => text,
+        // Numbers and booleans are matched exactly
+        other => return Ok(doc! { field: to_bson(other)? })
+    };
+
+    if phrase {
+        return Ok(regex(&field, escape(text), true))
+    };
+
+    let mut clauses: Vec<Document> = text
+        .split_whitespace()
+        .map(|term| regex(&field, format!("\\b{}\\b", escape(term)), true))
+        .collect();
+
+    match (clauses.len(), operator.as_str()) {
+        (0, _) => Ok(doc! {}),
+        (1, _) => Ok(clauses.remove(0)),
+        (_, "and") => Ok(doc! { "$and": clauses }),
+        _ => Ok(doc! { "$or": clauses })
+    }
+}
+
+fn range_query(body: &Value) -> BoxResult<Document> {
+    let (field, spec) = single_entry(body, "range")?;
+    let spec = match spec {
+        Value::Object(spec) => spec,
+        _ => return Err(MyError::bad_request("range expects an object of bounds"))
+    };
+
+    let mut bounds = Document::new();
+    for (bound, value) in spec {
+        let operator = match bound.as_str() {
+            "gt" => "$gt",
+            "gte" => "$gte",
+            "lt" => "$lt",
+            "lte" => "$lte",
+            // Dates are always parsed as ISO 8601 or date math
+            "format" | "time_zone" => continue,
+            other => return Err(MyError::bad_request(format!("unknown range bound {}", other)))
+        };
+        bounds.insert(operator, range_value(value)?);
+    }
+    Ok(doc! { field: bounds })
+}
+
+// Strings that look like dates are compared as dates, so ranges work on _time
+fn range_value(value: &Value) -> BoxResult<Bson> {
+    match value {
+        Value::String(s) => match parse_date(s) {
+            Some(date) => Ok(Bson::DateTime(date.into())),
+            None => Ok(Bson::String(s.clone()))
+        },
+        other => to_bson(other)
+    }
+}
+
+fn bool_query(body: &Value) -> BoxResult<Document> {
+    let mut clauses = Vec::new();
+    for occur in ["must", "filter"].iter() {
+        for query in as_list(body.get(*occur)) {
+            clauses.push(translate(query)?);
+        }
+    }
+
+    let should = as_list(body.get("should"))
+        .into_iter()
+        .map(translate)
+        .collect::<BoxResult<Vec<Document>>>()?;
+
+    // As in Elasticsearch, should clauses are optional when there is a must or filter
+    let minimum = body
+        .get("minimum_should_match")
+        .and_then(Value::as_i64)
+        .unwrap_or(if clauses.is_empty() { 1 } else { 0 });
+
+    if !should.is_empty() {
+        match minimum {
+            0 => (),
+            1 => clauses.push(doc! { "$or": should }),
+            _ => return Err(MyError::bad_request("minimum_should_match above 1 is not supported"))
+        }
+    };
+
+    let must_not = as_list(body.get("must_not"))
+        .into_iter()
+        .map(translate)
+        .collect::<BoxResult<Vec<Document>>>()?;
+    if !must_not.is_empty() {
+        clauses.push(doc! { "$nor": must_not });
+    };
+
+    match clauses.len() {
+        0 => Ok(doc! {}),
+        1 => Ok(clauses.remove(0)),
+        _ => Ok(doc! { "$and": clauses })
+    }
+}
+
+// Accepts "field", {"field": "desc"} or {"field": {"order": "desc"}}, or a list of them
+fn parse_sort(sort: &Value) -> BoxResult<Document> {
+    let mut result = Document::new();
+    for entry in as_list(Some(sort)) {
+        match entry {
+            Value::String(field) => {
+                result.insert(field.clone(), 1);
+            }
+            Value::Object(fields) => {
+                for (field, order) in fields {
+                    let order = match order {
+                        Value::Object(options) => options.get("order").and_then(Value::as_str).unwrap_or("asc"),
+                        other => other.as_str().unwrap_or("asc")
+                    };
+                    result.insert(field.clone(), if order == "desc" { -1 } else { 1 });
+                }
+            }
+            _ => return Err(MyError::bad_request("sort expects field names or objects"))
+        }
+    }
+
+    // There is no relevance score, so sorting on it is ignored
+    result.remove("_score");
+    Ok(result)
+}
+
+fn parse_source(source: &Value) -> BoxResult<Option<Document>> {
+    let (includes, excludes) = match source {
+        Value::Bool(true) => return Ok(None),
+        Value::Bool(false) => return Ok(Some(doc! { "_id": 1 })),
+        Value::Object(options) => (
+            as_list(options.get("includes")),
+            as_list(options.get("excludes"))
+        ),
+        other => (as_list(Some(other)), Vec::new())
+    };
+
+    // Mongodb cannot mix inclusion and exclusion, so includes win
+    let (fields, flag) = match includes.is_empty() {
+        true => (excludes, 0),
+        false => (includes, 1)
+    };
+
+    let mut projection = Document::new();
+    for field in fields {
+        projection.insert(as_str(field, "_source")?, flag);
+    }
+    match projection.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(projection))
+    }
+}
+
+// Shape results like an Elasticsearch hits response
+pub fn hits(collection: &str, total: u64, docs: Vec<Document>, took: i64) -> Document {
+    let hits: Vec<Document> = docs
+        .into_iter()
+        .map(|mut source| {
+            let id = source.remove("_id").map(|id| Bson::String(id_to_string(&id))).unwrap_or(Bson::Null);
+            doc! { "_index": collection, "_id": id, "_score": Bson::Null, "_source": source }
+        })
+        .collect();
+
+    doc! {
+        "took": took,
+        "timed_out": false,
+        "hits": {
+            "total": { "value": total as i64, "relation": "eq" },
+            "max_score": Bson::Null,
+            "hits": hits
+        }
+    }
+}
+
+// Parse ISO 8601 dates and times, along with date math like now-1d
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    match value.strip_prefix("now") {
+        Some(math) => date_math(math),
+        None => parse_timestamp(value)
+    }
+}
+
+fn date_math(math: &str) -> Option<DateTime<Utc>> {
+    let now = Utc::now();
+    if math.is_empty() {
+        return Some(now)
+    };
+
+    let (sign, rest) = match math.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return None
+    };
+    let unit = rest.chars().last()?;
+    let amount: i64 = rest[..rest.len() - unit.len_utf8()].parse().ok()?;
+    let duration = match unit {
+        's' => chrono::Duration::seconds(amount),
+        'm' => chrono::Duration::minutes(amount),
+        'h' | 'H' => chrono::Duration::hours(amount),
+        'd' => chrono::Duration::days(amount),
+        'w' => chrono::Duration::weeks(amount),
+        'M' => chrono::Duration::days(amount * 30),
+        'y' => chrono::Duration::days(amount * 365),
+        _ => return None
+    };
+    Some(now + duration * sign)
+}
+
+// Convert an Elasticsearch wildcard into an anchored regex
+pub fn wildcard_pattern(wildcard: &str) -> String {
+    let mut pattern = String::from("^");
+    for c in wildcard.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&escape(&c.to_string()))
+        }
+    }
+    pattern.push('$');
+    pattern
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$/".contains(c) {
+            escaped.push('\\');
+        };
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn regex(field: &str, pattern: String, case_insensitive: bool) -> Document {
+    match case_insensitive {
+        true => doc! { field: { "$regex": pattern, "$options": "i" } },
+        false => doc! { field: { "$regex": pattern } }
+    }
+}
+
+fn case_insensitive(body: &Value, field: &str) -> bool {
+    body.get(field)
+        .and_then(|spec| spec.get("case_insensitive"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+// Split {"field": value} into its only key and value
+fn single_entry<'a>(value: &'a Value, name: &str) -> BoxResult<(String, &'a Value)> {
+    match value.as_object() {
+        Some(map) if map.len() == 1 => {
+            let (key, value) = map.iter().next().expect("map has one entry");
+            Ok((key.clone(), value))
+        }
+        _ => Err(MyError::bad_request(format!("{} expects an object with a single key", name)))
+    }
+}
+
+// Accepts {"field": value} and {"field": {"<key>": value}}
+fn field_value<'a>(body: &'a Value, key: &str) -> BoxResult<(String, &'a Value)> {
+    let (field, spec) = single_entry(body, key)?;
+    match spec.get(key) {
+        Some(value) if spec.is_object() => Ok((field, value)),
+        _ => Ok((field, spec))
+    }
+}
+
+fn as_list(value: Option<&Value>) -> Vec<&Value> {
+    match value {
+        Some(Value::Array(values)) => values.iter().collect(),
+        Some(value) => vec![value],
+        None => Vec::new()
+    }
+}
+
+fn as_str<'a>(value: &'a Value, name: &str) -> BoxResult<&'a str> {
+    value.as_str().ok_or_else(|| MyError::bad_request(format!("{} expects a string", name)))
+}
+
+fn to_bson(value: &Value) -> BoxResult<Bson> {
+    Ok(Bson::try_from(value.clone())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn size_defaults_and_is_capped() {
+        assert_eq!(parse(&json!({})).unwrap().size, DEFAULT_SIZE);
+        assert_eq!(parse(&json!({"size": 50000})).unwrap().size, MAX_SIZE);
+    }
+
+    #[test]
+    fn size_zero_is_kept() {
+        assert_eq!(parse(&json!({"size": 0})).unwrap().size, 0);
+        assert_eq!(from_params(&params(&[("size", "0")])).unwrap().size, 0);
+    }
+
+    #[test]
+    fn negative_size_is_refused() {
+        assert!(parse(&json!({"size": -5})).is_err());
+        assert!(from_params(&params(&[("size", "-5")])).is_err());
+    }
+}
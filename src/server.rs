@@ -1,13 +1,27 @@
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, LOCATION};
+use hyper::body::Bytes;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use std::str::from_utf8;
 //use rust_tools::http::queries;
 use bson::doc;
-use rust_tools::bson::{to_doc, to_doc_vec};
 use rust_tools::strings::get_root_path;
+use std::collections::HashMap;
 use std::error::Error;
+use std::time::Instant;
 use clap::ArgMatches;
 use bson::document::Document;
+use bson::Bson;
+use mongodb::options::{CountOptions, FindOptions, Hint};
+use crate::aggs;
+use crate::compression::{self, Encoding};
 use crate::db;
+use crate::error::MyError;
+use crate::format::{self, Format, Input, Output};
+use crate::import;
+use crate::params;
+use crate::retention;
+use crate::search;
+use crate::table;
 
 type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
 
@@ -17,15 +31,31 @@ pub async fn main_handler(
     req: Request<Body>,
     db: db::DB,
 ) -> BoxResult<Response<Body>> {
+    // Tables set their own content type, and anything else falls back to JSON
+    let content_type = match Format::negotiate(req.uri(), req.headers()).unwrap_or_default() {
+        Format::Csv | Format::Tsv => Format::Relaxed.content_type(),
+        format => format.content_type()
+    };
+    let encoding = Encoding::negotiate(req.headers());
+    let compress_min_size = db.config.compress_min_size;
+
     match echo(opts, req, db).await {
-        Ok(s) => {
+        Ok(mut s) => {
             log::debug!("Handler got success");
-            Ok(s)
+            if !s.headers().contains_key(CONTENT_TYPE) && s.status() != StatusCode::NOT_MODIFIED {
+                s.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            };
+            compression::compress_response(s, encoding, compress_min_size).await
         }
         Err(e) => {
             log::error!("Handler caught error: {}", e);
-            let mut response = Response::new(Body::from(format!("{{\"error\" : \"{}\"}}", e)));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            let (status, body) = match e.downcast_ref::<MyError>() {
+                Some(err) => (err.status(), err.to_json()),
+                None => (StatusCode::INTERNAL_SERVER_ERROR, format!("{{\"error\" : \"{}\"}}", e))
+            };
+            let mut response = Response::new(Body::from(body));
+            *response.status_mut() = status;
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
             Ok(response)
         }
     }
@@ -34,12 +64,26 @@ pub async fn main_handler(
 // This is our service handler. It receives a Request, routes on its
 // path, and returns a Future of a Response.
 async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult<Response<Body>> {
+    let req = compression::decompress_request(req, db.config.max_body_size).await?;
 
     // Check if first folder in path is _cat
     // Get first segment in uri path, looking for _cat (for now)
     let chunks: Vec<&str> = req.uri().path().split("/").collect();
     let first = chunks.get(1).unwrap_or_else(|| &"na");
 
+    // Whether finds return _id, written as a string
+    let include_id = format::flag(req.uri(), "include_id")?.unwrap_or(db.config.include_id);
+
+    // Work out how documents should be written out
+    let output = Output {
+        format: Format::negotiate(req.uri(), req.headers())?,
+        dates: db.config.dates(first).cloned(),
+        pretty: format::flag(req.uri(), "pretty")?.unwrap_or(db.config.pretty),
+        envelope: format::flag(req.uri(), "envelope")?.unwrap_or(db.config.envelope),
+        string_ids: include_id,
+    };
+    let started = Instant::now();
+
     // Get path
     let path = &req.uri().path();
 
@@ -53,8 +97,7 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
         
                     match db.collections().await {
                         Ok(collections) => {
-                            let json_doc = serde_json::to_string(&collections)
-                                .expect("failed converting collection bson to json");
+                            let json_doc = output.render(&collections)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -71,8 +114,7 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
         
                     match db.rs_status().await {
                         Ok(results) => {
-                            let json_doc = serde_json::to_string(&results)
-                                .expect("failed converting collection bson to json");
+                            let json_doc = output.render(&results)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -89,8 +131,7 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
         
                     match db.get_log().await {
                         Ok(results) => {
-                            let json_doc = serde_json::to_string(&results)
-                                .expect("failed converting collection bson to json");
+                            let json_doc = output.render(&results)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -107,8 +148,7 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
         
                     match db.server_status().await {
                         Ok(results) => {
-                            let json_doc = serde_json::to_string(&results)
-                                .expect("failed converting collection bson to json");
+                            let json_doc = output.render(&results)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -125,8 +165,7 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
         
                     match db.inprog().await {
                         Ok(results) => {
-                            let json_doc = serde_json::to_string(&results)
-                                .expect("failed converting collection bson to json");
+                            let json_doc = output.render(&results)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -143,8 +182,24 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
         
                     match db.top().await {
                         Ok(results) => {
-                            let json_doc = serde_json::to_string(&results)
-                                .expect("failed converting collection bson to json");
+                            let json_doc = output.render(&results)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::GET, &"/_cat/retention") => {
+                    let path = req.uri().path();
+                    log::info!("Received GET to {}", &path);
+
+                    match retention::status(&db).await {
+                        Ok(results) => {
+                            let json_doc = output.render(&results)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -155,10 +210,7 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                         }
                     }
                 }
-                _ => Ok(Response::new(Body::from(format!(
-                    "{{ \"msg\" : \"{} is not a known path under /_cat\" }}",
-                    path
-                )))),
+                _ => Ok(Response::new(Body::from(output.render(&doc! { "msg": format!("{} is not a known path under /_cat", path) })?))),
             }
         },
         // Here, since _cat has been skipped, match Method and Last folder as action
@@ -167,19 +219,191 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
             let last = &req.uri().path().split("/").last().unwrap_or_else(|| "na");
 
             match (req.method(), last) {
+                (&Method::GET, _) if chunks.len() == 3 && is_document_id(chunks[2]) => {
+                    let path = req.uri().path();
+                    log::info!("Received GET to {}", &path);
+
+                    let id = db::parse_id(chunks[2])?;
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+
+                    let mongodoc = match db.get_by_id(&collection, &id).await? {
+                        Some(mongodoc) => mongodoc,
+                        None => return Err(Box::new(MyError::NotFound(format!("no doc with _id {} in {}", id, collection))))
+                    };
+
+                    // Let clients skip downloading a doc they already have
+                    let etag = db::etag(&mongodoc)?;
+                    if let Some(tags) = parts.headers.get(IF_NONE_MATCH) {
+                        if tags.to_str()?.split(',').any(|tag| tag.trim() == etag) {
+                            let mut response = Response::new(Body::empty());
+                            *response.status_mut() = StatusCode::NOT_MODIFIED;
+                            response.headers_mut().insert(ETAG, HeaderValue::from_str(&etag)?);
+                            return Ok(response)
+                        };
+                    };
+
+                    document_response(&output, mongodoc, StatusCode::OK)
+                },
+                (&Method::PUT, _) if chunks.len() == 3 && is_document_id(chunks[2]) => {
+                    let path = req.uri().path();
+                    log::info!("Received PUT to {}", &path);
+
+                    let id = db::parse_id(chunks[2])?;
+                    let collection = chunks[1].to_owned();
+                    let current = if_match(&db, &collection, &id, req.headers()).await?;
+                    let (collection, mongodoc) = data_to_bson(req).await?;
+
+                    match db.replace_by_id(opts, &collection, &id, mongodoc, current).await {
+                        Ok((true, mongodoc)) => document_response(&output, mongodoc, StatusCode::CREATED),
+                        Ok((false, mongodoc)) => document_response(&output, mongodoc, StatusCode::OK),
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::PATCH, _) if chunks.len() == 3 && is_document_id(chunks[2]) => {
+                    let path = req.uri().path();
+                    log::info!("Received PATCH to {}", &path);
+
+                    let id = db::parse_id(chunks[2])?;
+                    let collection = chunks[1].to_owned();
+                    let current = if_match(&db, &collection, &id, req.headers()).await?;
+                    let (collection, update) = data_to_bson(req).await?;
+
+                    match db.patch_by_id(opts, &collection, &id, update, current).await {
+                        Ok(mongodoc) => document_response(&output, mongodoc, StatusCode::OK),
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::DELETE, _) if chunks.len() == 3 && is_document_id(chunks[2]) => {
+                    let path = req.uri().path();
+                    log::info!("Received DELETE to {}", &path);
+
+                    let id = db::parse_id(chunks[2])?;
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+                    let current = if_match(&db, &collection, &id, &parts.headers).await?;
+
+                    match db.delete_by_id(opts, &collection, &id, current).await {
+                        Ok(_) => {
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": format!("Deleted doc {}", db::id_to_string(&id)) })?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::PUT, _) if chunks.len() == 2 => {
+                    let path = req.uri().path();
+                    log::info!("Received PUT to {}", &path);
+
+                    require_admin(&opts, &req)?;
+
+                    // Options are optional, so allow an empty body
+                    let (collection, input, body) = get_body(req).await?;
+                    let options = match format::is_blank(&body) {
+                        true => doc! {},
+                        false => input.parse_doc(&body)?
+                    };
+
+                    match db.create_collection(opts, &collection, options).await {
+                        Ok(_) => {
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": format!("Created collection {}", collection) })?));
+                            *response.status_mut() = StatusCode::CREATED;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::DELETE, _) if chunks.len() == 2 => {
+                    let path = req.uri().path();
+                    log::info!("Received DELETE to {}", &path);
+
+                    require_admin(&opts, &req)?;
+
+                    // Get short root path (the collection name)
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+
+                    // Require the collection name to be repeated, to guard against accidents
+                    if query_params(&parts).get("confirm") != Some(&collection) {
+                        return Err(MyError::bad_request(format!("pass confirm={} to drop this collection", collection)))
+                    };
+
+                    match db.drop_collection(opts, &collection).await {
+                        Ok(_) => {
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": format!("Dropped collection {}", collection) })?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::DELETE, _) if chunks.len() == 4 && chunks[2] == "_indexes" => {
+                    let path = req.uri().path();
+                    log::info!("Received DELETE to {}", &path);
+
+                    require_admin(&opts, &req)?;
+                    let name = chunks[3].to_owned();
+
+                    // Get short root path (the collection name)
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+
+                    match db.drop_index(opts, &collection, &name).await {
+                        Ok(doc) => {
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
                 (&Method::POST, &"_insert") => {
                     let path = req.uri().path();
                     log::info!("Received POST to {}", &path);
 
+                    // Pass return=document to get the doc back as stored
+                    let return_document = req.uri().query()
+                        .map(|q| url::form_urlencoded::parse(q.as_bytes()).any(|(k, v)| k == "return" && v == "document"))
+                        .unwrap_or(false);
+
                     // Get data and collection
                     let (collection, data) = data_to_bson(req).await?;
 
                     match db.insert(opts, &collection, data).await {
-                        Ok(_) => {
-                            let mut response = Response::new(Body::from(format!(
-                                "{{\"msg\" : \"Successfully saved\" }}"
-                            )));
-                            *response.status_mut() = StatusCode::OK;
+                        Ok(mongodoc) => {
+                            let id = mongodoc.get("_id").cloned().unwrap_or(Bson::Null);
+                            let location = format!("/{}/{}", collection, db::id_to_path(&id));
+                            let mut response = match return_document {
+                                true => document_response(&output, mongodoc, StatusCode::CREATED)?,
+                                false => {
+                                    let body = doc! { "msg": "Successfully saved", "_id": db::id_to_string(&id) };
+                                    let json_doc = output.render(&body)?;
+                                    let mut response = Response::new(Body::from(json_doc));
+                                    *response.status_mut() = StatusCode::CREATED;
+                                    response
+                                }
+                            };
+                            response.headers_mut().insert(LOCATION, HeaderValue::from_str(&location)?);
                             Ok(response)
                         }
                         Err(e) => {
@@ -197,9 +421,49 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
 
                     match db.update_one(opts, &collection, data).await {
                         Ok(body) => {
-                            let mut response = Response::new(Body::from(format!(
-                                "{{\"msg\" : \"{}\" }}", body
-                            )));
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": body })?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                }
+                (&Method::POST, &"_import") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    let (parts, body) = req.into_parts();
+                    let params = query_params(&parts);
+
+                    // TSV is picked by content type, and other delimiters can be passed as delimiter=
+                    let tsv = parts.headers.get(CONTENT_TYPE)
+                        .and_then(|h| h.to_str().ok())
+                        .map(|h| h.starts_with("text/tab-separated-values"))
+                        .unwrap_or(false);
+                    let delimiter = match params.get("delimiter").map(String::as_str) {
+                        Some("tab") | Some("\t") => b'\t',
+                        Some("comma") => b',',
+                        Some("semicolon") => b';',
+                        Some(d) if d.len() == 1 => d.as_bytes()[0],
+                        Some(d) => return Err(MyError::bad_request(format!("delimiter {} should be a single character, tab, comma or semicolon", d))),
+                        None if tsv => b'\t',
+                        None => b','
+                    };
+                    let types = match params.get("types") {
+                        Some(types) => import::parse_types(types)?,
+                        None => HashMap::new()
+                    };
+
+                    // Get data and collection
+                    let (collection, data) = get_data(Request::from_parts(parts, body)).await?;
+
+                    match import::import(&db, opts, &collection, &data, delimiter, &types).await {
+                        Ok(doc) => {
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
                         }
@@ -217,9 +481,50 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                     let (collection, data) = data_to_bson_vec(req).await?;
 
                     match db.insert_many(opts, &collection, data).await {
+                        Ok(ids) => {
+                            // Ids are keyed by the position of their doc in the request
+                            let mut ids: Vec<(usize, Bson)> = ids.into_iter().collect();
+                            ids.sort_by_key(|(index, _)| *index);
+                            let doc: Document = ids.into_iter().map(|(index, id)| (index.to_string(), id)).collect();
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                }
+                (&Method::GET, &"_find_one") => {
+                    log::info!("Received GET to {}", req.uri().path());
+
+                    // Get query parameters and collection
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+                    let request = params::parse(&query_params(&parts))?;
+
+                    let result = match request.is_plain() {
+                        true => db.findone(&collection, request.filter, request.projection, include_id).await,
+                        false => {
+                            let find_options = FindOptions::builder()
+                                .sort(request.sort.unwrap_or_else(|| doc! { "_id": -1 }))
+                                .projection(request.projection.unwrap_or_else(|| db::default_projection(include_id)))
+                                .skip(request.skip)
+                                .limit(1)
+                                .build();
+                            db.find_with_options(&collection, request.filter, find_options).await
+                                .map(|mut docs| match docs.is_empty() {
+                                    true => doc! { "msg": "no results found" },
+                                    false => docs.swap_remove(0)
+                                })
+                        }
+                    };
+
+                    match result {
                         Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                            let json_doc = output.render(&doc)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -237,10 +542,9 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                     // Get data and collection
                     let (collection, data) = data_to_bson(req).await?;
 
-                    match db.findone(&collection, data, None).await {
+                    match db.findone(&collection, data, None, include_id).await {
                         Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                            let json_doc = output.render(&doc)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -270,10 +574,49 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                         _ => Some(data.swap_remove(0))
                     };
 
-                    match db.findone(&collection, query, projection).await {
+                    match db.findone(&collection, query, projection, include_id).await {
                         Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                }
+                (&Method::GET, &"_find") => {
+                    log::info!("Received GET to {}", req.uri().path());
+
+                    // Get query parameters and collection
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+                    let request = params::parse(&query_params(&parts))?;
+
+                    let columns = request.projection.as_ref().and_then(table::projection_columns);
+                    let result = match request.is_plain() {
+                        true => db.find(&collection, request.filter, request.projection, include_id).await,
+                        false => {
+                            let find_options = FindOptions::builder()
+                                .sort(request.sort.unwrap_or_else(|| doc! { "_id": -1 }))
+                                .projection(request.projection.unwrap_or_else(|| db::default_projection(include_id)))
+                                .skip(request.skip)
+                                .build();
+                            let limit = match request.limit {
+                                Some(0) => return Err(MyError::bad_request("limit must be at least 1")),
+                                Some(limit) => (limit as i64).min(params::MAX_LIMIT),
+                                None => params::MAX_LIMIT
+                            };
+                            db.find_limited(&collection, request.filter, find_options, limit).await
+                        }
+                    };
+
+                    match result {
+                        Ok((docs, _)) if output.format.delimiter().is_some() => table::response(output.format, columns, docs),
+                        Ok((docs, truncated)) => {
+                            let json_doc = output.render_list(docs, truncated, started.elapsed().as_millis() as i64)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -291,10 +634,10 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                     // Get data and collection
                     let (collection, query) = data_to_bson(req).await?;
 
-                    match db.find(&collection, query, None).await {
-                        Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                    match db.find(&collection, query, None, include_id).await {
+                        Ok((docs, _)) if output.format.delimiter().is_some() => table::response(output.format, None, docs),
+                        Ok((docs, truncated)) => {
+                            let json_doc = output.render_list(docs, truncated, started.elapsed().as_millis() as i64)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -324,10 +667,78 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                         _ => Some(data.swap_remove(0))
                     };
 
-                    match db.find(&collection, query, projection).await {
-                        Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                    let columns = projection.as_ref().and_then(table::projection_columns);
+                    match db.find(&collection, query, projection, include_id).await {
+                        Ok((docs, _)) if output.format.delimiter().is_some() => table::response(output.format, columns, docs),
+                        Ok((docs, truncated)) => {
+                            let json_doc = output.render_list(docs, truncated, started.elapsed().as_millis() as i64)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                }
+                (&Method::POST, &"_search") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    // Get search request and collection, where an empty body matches everything
+                    let (collection, input, body) = get_body(req).await?;
+                    let body: serde_json::Value = match format::is_blank(&body) {
+                        true => serde_json::json!({}),
+                        false => input.parse_value(&body)?
+                    };
+                    let search = search::parse(&body)?;
+
+                    run_search(&db, &collection, search, &output).await
+                }
+                (&Method::GET, &"_search") => {
+                    log::info!("Received GET to {}", req.uri().path());
+
+                    // Get short root path (the collection name)
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+                    let search = search::from_params(&query_params(&parts))?;
+
+                    run_search(&db, &collection, search, &output).await
+                }
+                (&Method::POST, &"_text_search") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    // Get data and collection
+                    let (collection, data) = data_to_bson(req).await?;
+
+                    // The search string supports mongodb's "phrase" and -negation syntax
+                    let mut text = match data.get_str("search") {
+                        Ok(search) => doc! { "$search": search },
+                        Err(_) => return Err(MyError::bad_request("text search requires a \"search\" string"))
+                    };
+                    if let Ok(language) = data.get_str("language") {
+                        text.insert("$language", language);
+                    };
+                    if let Ok(case_sensitive) = data.get_bool("case_sensitive") {
+                        text.insert("$caseSensitive", case_sensitive);
+                    };
+                    if let Ok(diacritic_sensitive) = data.get_bool("diacritic_sensitive") {
+                        text.insert("$diacriticSensitive", diacritic_sensitive);
+                    };
+
+                    let filter = data.get_document("filter").cloned().unwrap_or_default();
+                    let projection = data.get_document("projection").ok().cloned();
+                    let limit = match get_integer(&data, "limit") {
+                        Some(limit) if limit < 1 => return Err(MyError::bad_request("limit must be at least 1")),
+                        Some(limit) => limit.min(params::MAX_LIMIT),
+                        None => params::MAX_LIMIT
+                    };
+
+                    match db.text_search(&collection, text, filter, projection, limit, include_id).await {
+                        Ok((docs, truncated)) => {
+                            let json_doc = output.render_list(docs, truncated, started.elapsed().as_millis() as i64)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -345,10 +756,16 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                     // Get data and collection
                     let (collection, data) = data_to_bson_vec(req).await?;
 
+                    // Tables are streamed as the pipeline returns results
+                    if output.format.delimiter().is_some() {
+                        let columns = table::pipeline_columns(&data);
+                        let cursor = db.aggregate_cursor(&collection, data).await?;
+                        return table::stream(output.format, columns, cursor)
+                    };
+
                     match db.aggregate(&collection, data).await {
-                        Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                        Ok(docs) => {
+                            let json_doc = output.render_list(docs, false, started.elapsed().as_millis() as i64)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -362,14 +779,102 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                 (&Method::GET, &"_count") => {
                     log::info!("Received GET to {}", req.uri().path());
 
-                    // Get short root path (the collection name)
+                    // Get query parameters and collection
                     let (parts, _body) = req.into_parts();
                     let collection = get_root_path(&parts);
+                    let request = params::parse(&query_params(&parts))?;
+
+                    // Without a filter the cheaper estimated count is used
+                    let result = match request.filter.is_empty() && request.is_plain() {
+                        true => db.count(&collection).await,
+                        false => {
+                            let count_options = CountOptions::builder()
+                                .limit(request.limit)
+                                .skip(request.skip)
+                                .build();
+                            db.count_documents(&collection, request.filter, count_options).await
+                        }
+                    };
+
+                    match result {
+                        Ok(doc) => {
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::POST, &"_count") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    // Get options and collection, where an empty body counts everything
+                    let (collection, input, body) = get_body(req).await?;
+                    let data = match format::is_blank(&body) {
+                        true => doc! {},
+                        false => input.parse_doc(&body)?
+                    };
+                    let filter = data.get_document("filter").cloned().unwrap_or_default();
 
-                    match db.count(&collection).await {
+                    let result = match data.get_bool("estimate").unwrap_or(false) {
+                        true if !filter.is_empty() => {
+                            return Err(MyError::bad_request("estimated counts cannot be filtered"))
+                        }
+                        true => db.count(&collection).await,
+                        false => {
+                            let hint = match data.get("hint") {
+                                Some(Bson::String(name)) => Some(Hint::Name(name.clone())),
+                                Some(Bson::Document(keys)) => Some(Hint::Keys(keys.clone())),
+                                _ => None
+                            };
+                            let count_options = CountOptions::builder()
+                                .limit(get_unsigned(&data, "limit")?)
+                                .skip(get_unsigned(&data, "skip")?)
+                                .hint(hint)
+                                .build();
+                            db.count_documents(&collection, filter, count_options).await
+                        }
+                    };
+
+                    match result {
                         Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::POST, &"_distinct") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    // Get data and collection
+                    let (collection, data) = data_to_bson(req).await?;
+
+                    let field = match data.get_str("field") {
+                        Ok(field) => field,
+                        Err(_) => return Err(MyError::bad_request("distinct requires a \"field\""))
+                    };
+                    let filter = data.get_document("filter").cloned().unwrap_or_default();
+
+                    let result = match data.get_bool("counts").unwrap_or(false) {
+                        true => db.distinct_counts(&collection, field, filter).await.map(|docs| output.render(&docs)),
+                        false => db.distinct(&collection, field, filter).await.map(|values| output.render(&values))
+                    };
+
+                    match result {
+                        Ok(json_doc) => {
+                            let json_doc = json_doc?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -389,8 +894,33 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
 
                     match db.get_indexes(&collection).await {
                         Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::POST, &"_indexes") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    require_admin(&opts, &req)?;
+
+                    // Accept either a single index, or a list of indexes
+                    let (collection, input, body) = get_body(req).await?;
+                    let indexes = match input.parse_bson(&body)? {
+                        Bson::Array(_) => input.parse_doc_vec(&body)?,
+                        _ => vec![input.parse_doc(&body)?]
+                    };
+
+                    match db.create_indexes(opts, &collection, indexes).await {
+                        Ok(doc) => {
+                            let json_doc = output.render(&doc)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -410,8 +940,114 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
 
                     match db.index_stats(&collection).await {
                         Ok(doc) => {
-                            let json_doc = serde_json::to_string(&doc)
-                                .expect("failed converting bson to json");
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::POST, &"_rename") => {
+                    let path = req.uri().path();
+                    log::info!("Received POST to {}", &path);
+
+                    require_admin(&opts, &req)?;
+
+                    // Get data and collection
+                    let (collection, data) = data_to_bson(req).await?;
+
+                    let to = match data.get_str("to") {
+                        Ok(to) => to,
+                        Err(_) => return Err(MyError::bad_request("rename requires a \"to\" collection"))
+                    };
+                    let drop_target = data.get_bool("drop_target").unwrap_or(false);
+
+                    match db.rename_collection(opts, &collection, to, drop_target).await {
+                        Ok(_) => {
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": format!("Renamed collection {} to {}", collection, to) })?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::GET, &"_stats") => {
+                    log::info!("Received GET to {}", req.uri().path());
+
+                    // Get short root path (the collection name)
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+
+                    match db.stats(&collection).await {
+                        Ok(doc) => {
+                            let json_doc = output.render(&doc)?;
+                            let mut response = Response::new(Body::from(json_doc));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::GET, &"_schema") => {
+                    log::info!("Received GET to {}", req.uri().path());
+
+                    // Get short root path (the collection name)
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+
+                    match db.get_schema(&collection).await {
+                        Ok(Some(schema)) => {
+                            let mut response = Response::new(Body::from(output.render(&schema)?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Ok(None) => Ok(Response::new(Body::from(output.render(&doc! { "msg": format!("No schema set for {}", collection) })?))),
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::PUT, &"_schema") => {
+                    let path = req.uri().path();
+                    log::info!("Received PUT to {}", &path);
+
+                    // Get schema and collection
+                    let (collection, input, body) = get_body(req).await?;
+                    let schema = input.parse_value(&body)?;
+
+                    match db.set_schema(opts, &collection, schema).await {
+                        Ok(_) => {
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": "Successfully saved schema" })?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                (&Method::GET, &"_validator") => {
+                    log::info!("Received GET to {}", req.uri().path());
+
+                    // Get short root path (the collection name)
+                    let (parts, _body) = req.into_parts();
+                    let collection = get_root_path(&parts);
+
+                    match db.get_validator(&collection).await {
+                        Ok(doc) => {
+                            let json_doc = output.render(&doc)?;
                             let mut response = Response::new(Body::from(json_doc));
                             *response.status_mut() = StatusCode::OK;
                             Ok(response)
@@ -422,16 +1058,154 @@ async fn echo(opts: ArgMatches<'_>, req: Request<Body>, db: db::DB) -> BoxResult
                         }
                     }
                 },
-                _ => Ok(Response::new(Body::from(format!(
-                    "{{ \"msg\" : \"{} {} is not a recognized action\" }}",
-                    req.method(),
-                    last)
-                ))),
+                (&Method::PUT, &"_validator") => {
+                    let path = req.uri().path();
+                    log::info!("Received PUT to {}", &path);
+
+                    // Get data and collection
+                    let (collection, data) = data_to_bson(req).await?;
+
+                    match db.set_validator(opts, &collection, data).await {
+                        Ok(body) => {
+                            let mut response = Response::new(Body::from(output.render(&doc! { "msg": body })?));
+                            *response.status_mut() = StatusCode::OK;
+                            Ok(response)
+                        }
+                        Err(e) => {
+                            log::error!("Got error {}", e);
+                            Err(e)
+                        }
+                    }
+                },
+                _ => Ok(Response::new(Body::from(output.render(&doc! { "msg": format!("{} {} is not a recognized action", req.method(), last) })?))),
             }
         }
     }
 }
 
+// Run a search, returning the results in Elasticsearch's format
+async fn run_search(db: &db::DB, collection: &str, search: search::SearchRequest, output: &Output) -> BoxResult<Response<Body>> {
+    let started = Instant::now();
+    let aggregations = match search.aggs.is_empty() {
+        true => None,
+        false => Some(aggs::evaluate(db, collection, search.filter.clone(), &search.aggs).await?)
+    };
+
+    let find_options = FindOptions::builder()
+        .sort(search.sort.unwrap_or_else(|| doc! { "_id": -1 }))
+        .projection(search.projection)
+        .skip(search.from)
+        .limit(search.size)
+        .build();
+
+    match db.search(collection, search.filter, find_options).await {
+        Ok((total, mut docs)) => {
+            let took = started.elapsed().as_millis() as i64;
+            output.restore_dates(&mut docs);
+            let mut results = search::hits(collection, total, docs, took);
+            if let Some(aggregations) = aggregations {
+                results.insert("aggregations", aggregations);
+            };
+            let json_doc = output.render(&results)?;
+            let mut response = Response::new(Body::from(json_doc));
+            *response.status_mut() = StatusCode::OK;
+            Ok(response)
+        }
+        Err(e) => {
+            log::error!("Got error {}", e);
+            Err(e)
+        }
+    }
+}
+
+// Read a whole number which cannot be negative, like a limit or skip
+fn get_unsigned(data: &Document, key: &str) -> BoxResult<Option<u64>> {
+    match get_integer(data, key) {
+        Some(i) if i < 0 => Err(MyError::bad_request(format!("{} cannot be negative", key))),
+        i => Ok(i.map(|i| i as u64))
+    }
+}
+
+// Read a whole number, whichever numeric type it was parsed as
+fn get_integer(data: &Document, key: &str) -> Option<i64> {
+    match data.get(key) {
+        Some(Bson::Int32(i)) => Some(*i as i64),
+        Some(Bson::Int64(i)) => Some(*i),
+        Some(Bson::Double(d)) if d.fract() == 0.0 => Some(*d as i64),
+        _ => None
+    }
+}
+
+// Create a hashmap of the url query parameters
+fn query_params(parts: &http::request::Parts) -> HashMap<String, String> {
+    match parts.uri.query() {
+        Some(query) => url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        None => HashMap::new()
+    }
+}
+
+// Action segments start with an underscore, anything else after the collection is a doc id
+fn is_document_id(segment: &str) -> bool {
+    !segment.is_empty() && !segment.starts_with('_')
+}
+
+// Respond with a single doc, with its _id as a string and its ETag
+fn document_response(output: &Output, mut mongodoc: Document, status: StatusCode) -> BoxResult<Response<Body>> {
+    let etag = db::etag(&mongodoc)?;
+    if let Some(id) = mongodoc.get("_id").map(db::id_to_string) {
+        mongodoc.insert("_id", id);
+    };
+
+    let json_doc = output.render(&mongodoc)?;
+    let mut response = Response::new(Body::from(json_doc));
+    *response.status_mut() = status;
+    response.headers_mut().insert(ETAG, HeaderValue::from_str(&etag)?);
+    Ok(response)
+}
+
+// With If-Match, return the current doc only if it is the version the client last read
+async fn if_match(db: &db::DB, collection: &str, id: &Bson, headers: &HeaderMap) -> BoxResult<Option<Document>> {
+    let tags = match headers.get(IF_MATCH) {
+        Some(tags) => tags.to_str()?.to_owned(),
+        None => return Ok(None)
+    };
+
+    let current = match db.get_by_id(collection, id).await? {
+        Some(current) => current,
+        None => return Err(Box::new(MyError::PreconditionFailed))
+    };
+
+    let etag = db::etag(&current)?;
+    match tags.split(',').map(str::trim).any(|tag| tag == "*" || tag == etag) {
+        true => Ok(Some(current)),
+        false => Err(Box::new(MyError::PreconditionFailed))
+    }
+}
+
+// Admin actions require the admin token to be passed as a bearer token
+fn require_admin(opts: &ArgMatches<'_>, req: &Request<Body>) -> BoxResult<()> {
+    let token = match opts.value_of("admin_token") {
+        Some(token) => token,
+        None => {
+            log::error!("Rejecting admin action, as no admin token is configured");
+            return Err(Box::new(MyError::Forbidden))
+        }
+    };
+
+    let supplied = req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+
+    match supplied == Some(token) {
+        true => Ok(()),
+        false => {
+            log::error!("Rejecting admin action, as the admin token is missing or wrong");
+            Err(Box::new(MyError::Forbidden))
+        }
+    }
+}
+
 pub async fn get_data(req: Request<Body>) -> BoxResult<(String, String)> {
     // Split apart request
     let (parts, body) = req.into_parts();
@@ -449,34 +1223,43 @@ pub async fn get_data(req: Request<Body>) -> BoxResult<(String, String)> {
     Ok((collection, value.to_owned()))
 }
 
+// Read the body, along with the format its content type says it is in
+pub async fn get_body(req: Request<Body>) -> BoxResult<(String, Input, Bytes)> {
+    let (parts, body) = req.into_parts();
+    let collection = get_root_path(&parts);
+    let input = Input::from_headers(&parts.headers);
+    let whole_body = hyper::body::to_bytes(body).await?;
+    Ok((collection, input, whole_body))
+}
+
 pub async fn data_to_bson(req: Request<Body>) -> BoxResult<(String, Document)> {
 
-    let (collection, value) = get_data(req).await?;
+    let (collection, input, body) = get_body(req).await?;
 
-    // Convert string to bson
-    let data = match to_doc(&value) {
+    // Convert body to bson
+    let data = match input.parse_doc(&body) {
         Ok(d) => d,
         Err(e) => return Err(e),
     };
 
     // Print out converted bson doc
-    log::debug!("Converted json into bson doc: {}", data);
+    log::debug!("Converted body into bson doc: {}", data);
 
     Ok((collection, data))
 }
 
 pub async fn data_to_bson_vec(req: Request<Body>) -> BoxResult<(String, Vec<Document>)> {
 
-    let (collection, value) = get_data(req).await?;
+    let (collection, input, body) = get_body(req).await?;
 
-    // Convert string to bson
-    let data = match to_doc_vec(&value) {
+    // Convert body to bson
+    let data = match input.parse_doc_vec(&body) {
         Ok(d) => d,
         Err(e) => return Err(e),
     };
 
     // Print out converted bson doc
-    log::debug!("Converted json into bson doc: {:?}", data);
+    log::debug!("Converted body into bson doc: {:?}", data);
 
     Ok((collection, data))
 }
@@ -0,0 +1,180 @@
+use bson::Bson;
+use futures::StreamExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response, StatusCode};
+use mongodb::bson::document::Document;
+use mongodb::Cursor;
+use std::error::Error;
+
+use crate::format::Format;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+
+// Documents read ahead to find the columns of a streamed result, when nothing names them
+const SAMPLE_SIZE: usize = 100;
+
+// Rows written before a streamed chunk is sent
+const CHUNK_ROWS: usize = 500;
+
+// Respond with a CSV or TSV table of the docs, with one column for every field found
+pub fn response(format: Format, columns: Option<Vec<String>>, docs: Vec<Document>) -> BoxResult<Response<Body>> {
+    let rows: Vec<Vec<(String, String)>> = docs.iter().map(flatten).collect();
+    let columns = columns.unwrap_or_else(|| union(&rows));
+
+    let mut writer = writer(format);
+    writer.write_record(&columns)?;
+    for row in rows.iter() {
+        writer.write_record(select(&columns, row))?;
+    }
+    let table = writer.into_inner().map_err(|e| e.to_string())?;
+
+    table_response(format, Body::from(table))
+}
+
+// Stream a cursor as a CSV or TSV table, sending chunks as rows are read
+pub fn stream(format: Format, columns: Option<Vec<String>>, mut cursor: Cursor<Document>) -> BoxResult<Response<Body>> {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        // Read ahead to find the columns, as the header has to come first
+        let mut pending = Vec::new();
+        if columns.is_none() {
+            while pending.len() < SAMPLE_SIZE {
+                match cursor.next().await {
+                    Some(Ok(doc)) => pending.push(flatten(&doc)),
+                    Some(Err(e)) => log::error!("Caught error, skipping: {}", e),
+                    None => break
+                }
+            }
+        };
+        let columns = columns.unwrap_or_else(|| union(&pending));
+
+        let mut writer = writer(format);
+        if let Err(e) = writer.write_record(&columns) {
+            log::error!("Failed writing table header: {}", e);
+            return
+        };
+        for row in pending.iter() {
+            let _ = writer.write_record(select(&columns, row));
+        }
+
+        let mut rows = pending.len();
+        loop {
+            let next = cursor.next().await;
+            if let Some(result) = &next {
+                match result {
+                    Ok(doc) => {
+                        let _ = writer.write_record(select(&columns, &flatten(doc)));
+                        rows += 1;
+                    }
+                    Err(e) => log::error!("Caught error, skipping: {}", e)
+                }
+            };
+
+            if next.is_none() || rows % CHUNK_ROWS == 0 {
+                let chunk = match writer.into_inner() {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        log::error!("Failed writing table rows: {}", e);
+                        return
+                    }
+                };
+                if !chunk.is_empty() && sender.send_data(Bytes::from(chunk)).await.is_err() {
+                    log::debug!("Client went away while streaming a table");
+                    return
+                };
+                writer = self::writer(format);
+            };
+
+            if next.is_none() {
+                break
+            };
+        }
+        log::debug!("Streamed {} rows", rows);
+    });
+
+    table_response(format, body)
+}
+
+// Columns named by an inclusion projection, in the order they were given
+pub fn projection_columns(projection: &Document) -> Option<Vec<String>> {
+    let columns: Vec<String> = projection
+        .iter()
+        .filter(|(_, v)| !matches!(v, Bson::Int32(0) | Bson::Int64(0) | Bson::Boolean(false)))
+        .filter(|(_, v)| !matches!(v, Bson::Double(d) if *d == 0.0))
+        .map(|(k, _)| k.clone())
+        .collect();
+    match columns.is_empty() {
+        true => None,
+        false => Some(columns)
+    }
+}
+
+// Columns named by the last $project stage of a pipeline, if it comes last
+pub fn pipeline_columns(pipeline: &[Document]) -> Option<Vec<String>> {
+    let stage = pipeline.last()?;
+    projection_columns(stage.get_document("$project").ok()?)
+}
+
+// Nested documents become dotted columns, anything else a single cell
+fn flatten(mongodoc: &Document) -> Vec<(String, String)> {
+    let mut row = Vec::new();
+    flatten_into(&mut row, None, mongodoc);
+    row
+}
+
+fn flatten_into(row: &mut Vec<(String, String)>, prefix: Option<&str>, mongodoc: &Document) {
+    for (key, value) in mongodoc {
+        let column = match prefix {
+            Some(prefix) => format!("{}.{}", prefix, key),
+            None => key.clone()
+        };
+        match value {
+            Bson::Document(child) => flatten_into(row, Some(&column), child),
+            value => row.push((column, cell(value)))
+        }
+    }
+}
+
+fn cell(value: &Bson) -> String {
+    match value {
+        Bson::Null => String::new(),
+        Bson::String(s) => s.clone(),
+        value => match Format::Plain.to_value(value.clone()) {
+            serde_json::Value::String(s) => s,
+            other => other.to_string()
+        }
+    }
+}
+
+// Every column in the rows, in the order they were first seen
+fn union(rows: &[Vec<(String, String)>]) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    for (column, _) in rows.iter().flatten() {
+        if !columns.contains(column) {
+            columns.push(column.clone());
+        }
+    }
+    columns
+}
+
+fn select<'a>(columns: &'a [String], row: &'a [(String, String)]) -> impl Iterator<Item = &'a str> {
+    columns.iter().map(move |column| {
+        row.iter().find(|(c, _)| c == column).map(|(_, v)| v.as_str()).unwrap_or("")
+    })
+}
+
+fn writer(format: Format) -> csv::Writer<Vec<u8>> {
+    csv::WriterBuilder::new()
+        .delimiter(format.delimiter().unwrap_or(b','))
+        .from_writer(Vec::new())
+}
+
+fn table_response(format: Format, body: Body) -> BoxResult<Response<Body>> {
+    let content_type = format.content_type();
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::OK;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Ok(response)
+}
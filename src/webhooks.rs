@@ -0,0 +1,194 @@
+use chrono::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use mongodb::bson::{doc, document::Document};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use sha2::Sha256;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::config::WebhookConfig;
+use crate::db::DB;
+
+type BoxResult<T> = Result<T,Box<dyn Error + Send + Sync>>;
+type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+
+// Collection holding pending and failed deliveries, so nothing is lost across restarts
+pub const QUEUE_COLLECTION: &str = "_json_bucket_webhooks";
+
+const MAX_ATTEMPTS: i32 = 12;
+const BASE_DELAY_SECS: i64 = 5;
+const MAX_DELAY_SECS: i64 = 3600;
+const LEASE_SECS: i64 = 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Queue a delivery for every webhook configured on the collection
+pub async fn enqueue(db: &DB, collection: &str, event: &str, docs: &[Document]) {
+    let hooks = db.config.webhooks(collection);
+    if hooks.is_empty() {
+        return
+    };
+
+    let now = Utc::now();
+    let mut deliveries = Vec::new();
+    for hook in hooks {
+        for mongodoc in docs {
+            deliveries.push(doc! {
+                "collection": collection,
+                "event": event,
+                "url": &hook.url,
+                "payload": project(hook, mongodoc),
+                "status": "pending",
+                "attempts": 0,
+                "next_attempt": now,
+                "created": now
+            });
+        }
+    }
+
+    log::debug!("Queueing {} webhook deliveries for {}.{}", deliveries.len(), db.db, collection);
+
+    let queue = db.client.database(&db.db).collection::<Document>(QUEUE_COLLECTION);
+    match queue.insert_many(deliveries, None).await {
+        Ok(_) => db.webhook_notify.notify_one(),
+        Err(e) => log::error!("Error queueing webhooks for {}: {}", collection, e)
+    }
+}
+
+// Background task delivering queued webhooks, retrying failures with exponential backoff
+pub async fn worker(db: DB) {
+    let client: HttpsClient = Client::builder().build(HttpsConnector::new());
+
+    loop {
+        loop {
+            match next_delivery(&db).await {
+                Ok(Some(delivery)) => {
+                    if let Err(e) = deliver(&db, &client, delivery).await {
+                        log::error!("Error updating webhook queue: {}", e);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("Error reading webhook queue: {}", e);
+                    break
+                }
+            }
+        }
+
+        // Wait for new deliveries, or for retries to become due
+        let _ = tokio::time::timeout(POLL_INTERVAL, db.webhook_notify.notified()).await;
+    }
+}
+
+// Claim the next due delivery, leasing it so other instances skip it while we work
+async fn next_delivery(db: &DB) -> BoxResult<Option<Document>> {
+    let now = Utc::now();
+    let lease = now + chrono::Duration::seconds(LEASE_SECS);
+
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "next_attempt": 1 })
+        .return_document(ReturnDocument::Before)
+        .build();
+
+    let queue = db.client.database(&db.db).collection::<Document>(QUEUE_COLLECTION);
+    let delivery = queue.find_one_and_update(
+        doc! { "status": "pending", "next_attempt": { "$lte": now } },
+        doc! { "$set": { "next_attempt": lease } },
+        options
+    ).await?;
+
+    Ok(delivery)
+}
+
+async fn deliver(db: &DB, client: &HttpsClient, delivery: Document) -> BoxResult<()> {
+    let queue = db.client.database(&db.db).collection::<Document>(QUEUE_COLLECTION);
+    let id = delivery.get_object_id("_id")?;
+    let collection = delivery.get_str("collection")?;
+    let url = delivery.get_str("url")?;
+    let attempts = delivery.get_i32("attempts").unwrap_or(0) + 1;
+
+    // The secret is looked up at send time, so it never needs to be stored in the queue
+    let hook = match db.config.webhooks(collection).iter().find(|h| h.url == url) {
+        Some(hook) => hook,
+        None => {
+            log::warn!("Dropping webhook for {}, as {} is no longer configured", collection, url);
+            queue.delete_one(doc! { "_id": id }, None).await?;
+            return Ok(())
+        }
+    };
+
+    let body = serde_json::to_string(&doc! {
+        "collection": collection,
+        "event": delivery.get_str("event")?,
+        "document": delivery.get_document("payload")?
+    })?;
+
+    match send(client, hook, body).await {
+        Ok(_) => {
+            log::debug!("Delivered webhook to {}", url);
+            queue.delete_one(doc! { "_id": id }, None).await?;
+        }
+        Err(e) if attempts >= MAX_ATTEMPTS => {
+            log::error!("Giving up on webhook to {} after {} attempts: {}", url, attempts, e);
+            queue.update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "status": "failed", "attempts": attempts, "error": e.to_string() } },
+                None
+            ).await?;
+        }
+        Err(e) => {
+            let delay = (BASE_DELAY_SECS << (attempts - 1).min(20)).min(MAX_DELAY_SECS);
+            let next_attempt = Utc::now() + chrono::Duration::seconds(delay);
+            log::warn!("Webhook to {} failed, retrying in {}s: {}", url, delay, e);
+            queue.update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "attempts": attempts, "next_attempt": next_attempt, "error": e.to_string() } },
+                None
+            ).await?;
+        }
+    };
+
+    Ok(())
+}
+
+async fn send(client: &HttpsClient, hook: &WebhookConfig, body: String) -> BoxResult<()> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(&hook.url)
+        .header("Content-Type", "application/json")
+        .header("User-Agent", "json-bucket");
+
+    if let Some(secret) = &hook.secret {
+        request = request.header("X-Bucket-Signature", format!("sha256={}", sign(secret, &body)));
+    };
+
+    let request = request.body(Body::from(body))?;
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, client.request(request)).await??;
+
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("endpoint returned {}", response.status()).into())
+    }
+}
+
+// Hex encoded HMAC-SHA256 of the body
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn project(hook: &WebhookConfig, mongodoc: &Document) -> Document {
+    match &hook.fields {
+        Some(fields) => mongodoc
+            .iter()
+            .filter(|(k, _)| fields.iter().any(|f| f == *k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        None => mongodoc.clone()
+    }
+}